@@ -1,20 +1,39 @@
+use crate::routes::events::event_routes;
+use crate::routes::jobs::job_routes;
 use crate::routes::radarr::radarr_routes;
 use crate::routes::sonarr::sonarr_routes;
 use crate::services::task::TaskService;
 use crate::state::{AppArgs, AppState};
 use axum::Router;
 use clap::Parser;
+use lib::profile::Profile;
 use std::sync::Arc;
 use tower_http::trace::TraceLayer;
 pub type AppRouter = Router<AppState>;
 
 pub async fn create_app() -> Router {
     let args = Arc::new(AppArgs::parse());
-    let task_service = Arc::new(TaskService::new(args.clone()));
+    let profile = Arc::new(
+        Profile::load(&args.config.ffmpeg)
+            .await
+            .expect("Failed to load profile"),
+    );
+    let task_service = Arc::new(
+        TaskService::new(args.clone(), profile.clone())
+            .await
+            .expect("Failed to load job queue"),
+    );
+    task_service.start();
 
-    let app_state = AppState { args, task_service };
+    let app_state = AppState {
+        args,
+        profile,
+        task_service,
+    };
 
     Router::new()
+        .nest("/events", event_routes())
+        .nest("/jobs", job_routes())
         .nest("/radarr", radarr_routes())
         .nest("/sonarr", sonarr_routes())
         .layer(TraceLayer::new_for_http())
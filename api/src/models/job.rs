@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Job {
+    pub id: u64,
+
+    pub input_path: String,
+
+    pub output_path: String,
+
+    pub status: JobStatus,
+
+    pub error: Option<String>,
+
+    pub created_at: u64,
+
+    pub updated_at: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct JobProgress {
+    pub percent: f64,
+
+    pub out_time: u64,
+
+    pub speed: String,
+
+    pub eta: Option<u64>,
+
+    pub pass: u8,
+
+    pub passes: u8,
+}
+
+#[derive(Serialize)]
+pub struct JobResponse {
+    #[serde(flatten)]
+    pub job: Job,
+
+    pub progress: Option<JobProgress>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct JobFailure {
+    pub message: String,
+
+    pub stderr: Vec<String>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct JobQuality {
+    /// `vmaf`, `ssim` or `psnr`
+    pub metric: String,
+
+    pub score: f64,
+
+    pub samples: u32,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum JobEventKind {
+    Start,
+    Progress,
+    Warning,
+    Done,
+    Error,
+    Cancelled,
+}
+
+#[derive(Serialize, Clone)]
+pub struct JobEvent {
+    pub job_id: u64,
+
+    pub kind: JobEventKind,
+
+    pub input_path: String,
+
+    pub output_path: String,
+
+    pub progress: Option<JobProgress>,
+
+    pub error: Option<JobFailure>,
+
+    pub quality: Option<JobQuality>,
+
+    pub warning: Option<String>,
+}
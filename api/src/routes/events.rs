@@ -0,0 +1,25 @@
+use crate::{app::AppRouter, state::AppState};
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::get,
+};
+use std::convert::Infallible;
+use tokio_stream::{Stream, StreamExt, wrappers::BroadcastStream};
+
+pub fn event_routes() -> AppRouter {
+    AppRouter::new().route("/", get(stream_events))
+}
+
+async fn stream_events(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = BroadcastStream::new(state.task_service.subscribe_events()).filter_map(|event| {
+        event
+            .ok()
+            .and_then(|event| Event::default().json_data(event).ok())
+            .map(Ok)
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
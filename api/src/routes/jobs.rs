@@ -0,0 +1,42 @@
+use crate::{app::AppRouter, models::job::JobResponse, state::AppState};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+};
+use log::error;
+
+pub fn job_routes() -> AppRouter {
+    AppRouter::new()
+        .route("/", get(list_jobs))
+        .route("/{id}", get(get_job).delete(cancel_job))
+}
+
+async fn list_jobs(State(state): State<AppState>) -> Json<Vec<JobResponse>> {
+    Json(state.task_service.list_jobs().await)
+}
+
+async fn get_job(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<JobResponse>, StatusCode> {
+    match state.task_service.get_job(id).await {
+        Some(job) => Ok(Json(job)),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+async fn cancel_job(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<JobResponse>, StatusCode> {
+    match state.task_service.cancel_job(id).await {
+        Ok(Some(job)) => Ok(Json(job)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Failed to cancel job #{}: {}", id, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
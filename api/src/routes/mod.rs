@@ -1,2 +1,4 @@
+pub mod events;
+pub mod jobs;
 pub mod radarr;
 pub mod sonarr;
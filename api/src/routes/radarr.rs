@@ -1,9 +1,8 @@
 use crate::{app::AppRouter, models::radarr::RadarrWebhook, state::AppState};
 use axum::{Json, extract::State, routing::post};
 use lib::utils::get_output_file_name;
-use log::warn;
+use log::{error, warn};
 use std::path::Path;
-use tokio::task;
 
 pub fn radarr_routes() -> AppRouter {
     AppRouter::new().route("/", post(handle_webhook))
@@ -29,12 +28,12 @@ async fn handle_webhook(State(state): State<AppState>, Json(body): Json<RadarrWe
 
     let input_path = folder_path.join(&movie_file.relative_path);
 
-    let output_path = folder_path.join(get_output_file_name(&format!(
-        "{} {}",
-        &body.movie.title, &body.movie.year
-    )));
+    let output_path = folder_path.join(get_output_file_name(
+        &format!("{} {}", &body.movie.title, &body.movie.year),
+        &state.profile,
+    ));
 
-    task::spawn(async move {
-        state.task_service.run_task(&input_path, &output_path).await;
-    });
+    if let Err(e) = state.task_service.enqueue(&input_path, &output_path).await {
+        error!("Failed to queue job: {}", e);
+    }
 }
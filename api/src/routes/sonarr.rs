@@ -1,9 +1,8 @@
 use crate::{app::AppRouter, models::sonarr::SonarrWebhook, state::AppState};
 use axum::{Json, extract::State, routing::post};
 use lib::utils::get_output_file_name;
-use log::warn;
+use log::{error, warn};
 use std::path::Path;
-use tokio::task;
 
 pub fn sonarr_routes() -> AppRouter {
     AppRouter::new().route("/", post(handle_webhook))
@@ -31,12 +30,15 @@ async fn handle_webhook(State(state): State<AppState>, Json(body): Json<SonarrWe
 
     let folder_path = input_path.parent().expect("Invalid episode path");
 
-    let output_path = folder_path.join(get_output_file_name(&format!(
-        "{} S{:02}E{:02}",
-        &body.series.title, episode.season_number, episode.episode_number
-    )));
+    let output_path = folder_path.join(get_output_file_name(
+        &format!(
+            "{} S{:02}E{:02}",
+            &body.series.title, episode.season_number, episode.episode_number
+        ),
+        &state.profile,
+    ));
 
-    task::spawn(async move {
-        state.task_service.run_task(&input_path, &output_path).await;
-    });
+    if let Err(e) = state.task_service.enqueue(&input_path, &output_path).await {
+        error!("Failed to queue job: {}", e);
+    }
 }
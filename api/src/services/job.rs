@@ -0,0 +1,170 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{fs, sync::Mutex};
+
+use crate::models::job::{Job, JobStatus};
+
+const JOURNAL_FILE_NAME: &str = "jobs.json";
+
+pub struct JobStore {
+    path: PathBuf,
+    max_finished: usize,
+    jobs: Mutex<Vec<Job>>,
+}
+
+/// Whether two jobs read or write the same file.
+fn shares_paths(job: &Job, other: &Job) -> bool {
+    job.input_path == other.input_path || job.output_path == other.output_path
+}
+
+fn is_finished(job: &Job) -> bool {
+    matches!(
+        job.status,
+        JobStatus::Done | JobStatus::Failed | JobStatus::Cancelled
+    )
+}
+
+/// Drops the oldest finished jobs past `max_finished`. The newest one is
+/// always kept so ids are not handed out twice.
+fn prune(jobs: &mut Vec<Job>, max_finished: usize) {
+    let mut finished: Vec<u64> = jobs
+        .iter()
+        .filter(|job| is_finished(job))
+        .map(|job| job.id)
+        .collect();
+    if finished.len() <= max_finished {
+        return;
+    }
+    finished.sort_unstable();
+    let min_id = finished[finished.len() - max_finished.max(1)];
+    jobs.retain(|job| !is_finished(job) || job.id >= min_id);
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl JobStore {
+    /// Loads the journal from `data_dir`, creating the folder if needed.
+    ///
+    /// Jobs that were running when the process stopped are put back in the
+    /// queue so they get picked up again. Only the last `max_finished` done,
+    /// failed or cancelled jobs are kept.
+    pub async fn load(data_dir: &Path, max_finished: usize) -> io::Result<Self> {
+        fs::create_dir_all(data_dir).await?;
+
+        let path = data_dir.join(JOURNAL_FILE_NAME);
+        let mut jobs: Vec<Job> = match fs::read(&path).await {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+
+        for job in jobs.iter_mut() {
+            if job.status == JobStatus::Running {
+                job.status = JobStatus::Pending;
+                job.updated_at = now();
+            }
+        }
+        prune(&mut jobs, max_finished);
+
+        let store = Self {
+            path,
+            max_finished,
+            jobs: Mutex::new(jobs),
+        };
+        store.save(&store.jobs.lock().await).await?;
+
+        Ok(store)
+    }
+
+    async fn save(&self, jobs: &[Job]) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("json.part");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(jobs)?).await?;
+        fs::rename(&tmp_path, &self.path).await
+    }
+
+    /// Queues a job, unless a pending or running one already reads or writes
+    /// the same files, in which case that one is returned with `false`.
+    pub async fn push(&self, input_path: &Path, output_path: &Path) -> io::Result<(Job, bool)> {
+        let mut jobs = self.jobs.lock().await;
+        let timestamp = now();
+        let job = Job {
+            id: jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1,
+            input_path: input_path.display().to_string(),
+            output_path: output_path.display().to_string(),
+            status: JobStatus::Pending,
+            error: None,
+            created_at: timestamp,
+            updated_at: timestamp,
+        };
+        if let Some(existing) = jobs.iter().find(|other| {
+            matches!(other.status, JobStatus::Pending | JobStatus::Running)
+                && shares_paths(&job, other)
+        }) {
+            return Ok((existing.clone(), false));
+        }
+        jobs.push(job.clone());
+        self.save(&jobs).await?;
+        Ok((job, true))
+    }
+
+    pub async fn list(&self) -> Vec<Job> {
+        self.jobs.lock().await.clone()
+    }
+
+    pub async fn get(&self, id: u64) -> Option<Job> {
+        self.jobs
+            .lock()
+            .await
+            .iter()
+            .find(|job| job.id == id)
+            .cloned()
+    }
+
+    /// Marks the oldest pending job as running and returns it, skipping the
+    /// ones sharing files with a running job.
+    pub async fn take_next(&self) -> io::Result<Option<Job>> {
+        let mut jobs = self.jobs.lock().await;
+        let Some(index) = jobs.iter().position(|job| {
+            job.status == JobStatus::Pending
+                && !jobs
+                    .iter()
+                    .any(|other| other.status == JobStatus::Running && shares_paths(job, other))
+        }) else {
+            return Ok(None);
+        };
+        let job = &mut jobs[index];
+        job.status = JobStatus::Running;
+        job.updated_at = now();
+        let job = job.clone();
+        self.save(&jobs).await?;
+        Ok(Some(job))
+    }
+
+    pub async fn set_status(
+        &self,
+        id: u64,
+        status: JobStatus,
+        error: Option<String>,
+    ) -> io::Result<Option<Job>> {
+        let mut jobs = self.jobs.lock().await;
+        let Some(job) = jobs.iter_mut().find(|job| job.id == id) else {
+            return Ok(None);
+        };
+        job.status = status;
+        job.error = error;
+        job.updated_at = now();
+        let job = job.clone();
+        prune(&mut jobs, self.max_finished);
+        self.save(&jobs).await?;
+        Ok(Some(job))
+    }
+}
@@ -1,49 +1,376 @@
 use lib::{
+    clean_tmp_files,
     discord::{DiscordEventHandler, DiscordWebhook},
-    ffmpeg::FFMpeg,
+    error::Error,
+    ffmpeg::{FFMpeg, FFMpegCancelToken, FFMpegContext, FFMpegEvent, FFMpegProgress},
     ffprobe::ffprobe,
+    profile::Profile,
 };
-use log::{error, info};
-use std::{path::Path, sync::Arc};
-use tokio::{sync::Mutex, task::JoinSet};
+use log::{error, info, warn};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        Mutex, Notify,
+        broadcast::{self, Receiver, error::RecvError},
+    },
+    task::JoinSet,
+    time,
+};
+
+use crate::{
+    models::job::{
+        Job, JobEvent, JobEventKind, JobFailure, JobProgress, JobQuality, JobResponse, JobStatus,
+    },
+    services::job::JobStore,
+    state::AppArgs,
+};
+
+struct RunningJob {
+    cancel_token: FFMpegCancelToken,
+    progress: Option<JobProgress>,
+}
+
+type RunningJobs = Arc<Mutex<HashMap<u64, RunningJob>>>;
 
-use crate::state::AppArgs;
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
 
 pub struct TaskService {
-    mutex: Arc<Mutex<()>>,
     args: Arc<AppArgs>,
+    profile: Arc<Profile>,
+    store: JobStore,
+    notify: Notify,
+    running: RunningJobs,
+    events: broadcast::Sender<JobEvent>,
 }
 
 impl TaskService {
-    pub fn new(args: Arc<AppArgs>) -> Self {
-        Self {
-            mutex: Arc::new(Mutex::new(())),
+    pub async fn new(args: Arc<AppArgs>, profile: Arc<Profile>) -> io::Result<Self> {
+        let store =
+            JobStore::load(Path::new(args.data_dir.as_str()), args.max_finished_jobs).await?;
+        Ok(Self {
             args,
+            profile,
+            store,
+            notify: Notify::new(),
+            running: Arc::new(Mutex::new(HashMap::new())),
+            events: broadcast::channel(64).0,
+        })
+    }
+
+    /// Spawns the workers that process queued jobs, starting with the ones
+    /// left pending by a previous run.
+    pub fn start(self: &Arc<Self>) {
+        for _ in 0..self.args.max_concurrent_jobs {
+            let service = self.clone();
+            tokio::spawn(async move {
+                service.work().await;
+            });
         }
+
+        let service = self.clone();
+        tokio::spawn(async move {
+            service.clean().await;
+        });
     }
 
-    pub async fn run_task(&self, input_path: &Path, output_path: &Path) {
-        info!("Transcoding: {:?} to {:?}", input_path, output_path);
+    /// Periodically removes temporary outputs left over by interrupted
+    /// transcodes under the root folder and in the scratch directory.
+    async fn clean(&self) {
+        let config = &self.args.config.ffmpeg;
+        let mut roots = vec![(PathBuf::from(&self.args.root_folder_path), true)];
+        if let Some(scratch_dir) = &config.scratch_dir {
+            roots.push((PathBuf::from(scratch_dir), false));
+        }
 
-        let probe = ffprobe(&input_path).await.expect("ffprobe failed");
-        let mut ffmpeg = FFMpeg::new(&self.args.config.ffmpeg);
+        loop {
+            for (root, recursive) in roots.iter() {
+                let cleaned =
+                    clean_tmp_files(root, recursive, config.stale_tmp_age(), &self.profile).await;
+                for path in cleaned.removed {
+                    info!("Removed stale temporary file {:?}", path);
+                }
+                for e in cleaned.errors {
+                    error!("Failed to clean {:?}: {}", root, e);
+                }
+            }
+            time::sleep(CLEANUP_INTERVAL).await;
+        }
+    }
+
+    pub async fn enqueue(&self, input_path: &Path, output_path: &Path) -> io::Result<Job> {
+        let (job, created) = self.store.push(input_path, output_path).await?;
+        if created {
+            info!("Queued job #{}: {:?}", job.id, input_path);
+            self.notify.notify_one();
+        } else {
+            info!("Job #{} already handles {:?}", job.id, input_path);
+        }
+        Ok(job)
+    }
+
+    pub fn subscribe_events(&self) -> Receiver<JobEvent> {
+        self.events.subscribe()
+    }
+
+    async fn to_response(&self, job: Job) -> JobResponse {
+        let progress = self
+            .running
+            .lock()
+            .await
+            .get(&job.id)
+            .and_then(|running_job| running_job.progress.clone());
+        JobResponse { job, progress }
+    }
+
+    pub async fn list_jobs(&self) -> Vec<JobResponse> {
+        let mut responses = vec![];
+        for job in self.store.list().await {
+            responses.push(self.to_response(job).await);
+        }
+        responses
+    }
+
+    pub async fn get_job(&self, id: u64) -> Option<JobResponse> {
+        match self.store.get(id).await {
+            Some(job) => Some(self.to_response(job).await),
+            None => None,
+        }
+    }
+
+    /// Cancels a job: pending jobs are dropped from the queue, running jobs
+    /// have their ffmpeg process killed.
+    pub async fn cancel_job(&self, id: u64) -> io::Result<Option<JobResponse>> {
+        let running = self.running.lock().await;
+        let job = match running.get(&id) {
+            Some(running_job) => {
+                running_job.cancel_token.cancel();
+                self.store.get(id).await
+            }
+            None => match self.store.get(id).await {
+                Some(job) if job.status == JobStatus::Pending => {
+                    self.store
+                        .set_status(id, JobStatus::Cancelled, None)
+                        .await?
+                }
+                job => job,
+            },
+        };
+        drop(running);
+
+        Ok(match job {
+            Some(job) => Some(self.to_response(job).await),
+            None => None,
+        })
+    }
+
+    async fn take_next(&self) -> io::Result<Option<(Job, FFMpegCancelToken)>> {
+        let mut running = self.running.lock().await;
+        let Some(job) = self.store.take_next().await? else {
+            return Ok(None);
+        };
+        let cancel_token = FFMpegCancelToken::default();
+        running.insert(
+            job.id,
+            RunningJob {
+                cancel_token: cancel_token.clone(),
+                progress: None,
+            },
+        );
+        Ok(Some((job, cancel_token)))
+    }
+
+    async fn work(&self) {
+        loop {
+            match self.take_next().await {
+                Ok(Some((job, cancel_token))) => self.run_job(job, cancel_token).await,
+                Ok(None) => self.notify.notified().await,
+                Err(e) => {
+                    error!("Failed to read job queue: {}", e);
+                    self.notify.notified().await;
+                }
+            }
+        }
+    }
+
+    async fn run_job(&self, job: Job, cancel_token: FFMpegCancelToken) {
+        let input_path = PathBuf::from(&job.input_path);
+        let output_path = PathBuf::from(&job.output_path);
+
+        let (status, reason) = match self
+            .run_task(job.id, &input_path, &output_path, cancel_token)
+            .await
+        {
+            Ok(_) => (JobStatus::Done, None),
+            Err(Error::Cancelled) => {
+                info!("Cancelled job #{}", job.id);
+                (JobStatus::Cancelled, None)
+            }
+            Err(e) => {
+                error!("An error happened while transcoding job #{}: {}", job.id, e);
+                (JobStatus::Failed, Some(e.to_string()))
+            }
+        };
+
+        self.running.lock().await.remove(&job.id);
+
+        if let Err(e) = self.store.set_status(job.id, status, reason).await {
+            error!("Failed to update job #{}: {}", job.id, e);
+        }
+    }
+
+    fn get_progress(context: &FFMpegContext, progress: &FFMpegProgress) -> JobProgress {
+        let duration = context.probe.format.duration.parse::<f64>().unwrap_or(0.0);
+        let out_time = progress.out_time_us as f64 / 1_000_000.0;
+        // Every pass goes through the whole file
+        let total_duration = duration * progress.passes as f64;
+        let total_out_time =
+            progress.total_out_time_us((duration * 1_000_000.0) as u64) as f64 / 1_000_000.0;
+        let percent = if total_duration > 0.0 {
+            (total_out_time / total_duration * 100.0).min(100.0)
+        } else {
+            0.0
+        };
+        let eta = match progress.speed.trim_end_matches('x').parse::<f64>() {
+            Ok(speed) if speed > 0.0 => {
+                Some(((total_duration - total_out_time).max(0.0) / speed) as u64)
+            }
+            _ => None,
+        };
+        JobProgress {
+            percent,
+            out_time: out_time as u64,
+            speed: progress.speed.clone(),
+            eta,
+            pass: progress.pass,
+            passes: progress.passes,
+        }
+    }
+
+    /// Keeps the job's progress up to date and forwards its ffmpeg events to
+    /// the `/events` subscribers.
+    async fn relay_events(
+        job_id: u64,
+        running: RunningJobs,
+        events: broadcast::Sender<JobEvent>,
+        mut rx: Receiver<FFMpegEvent>,
+    ) {
+        loop {
+            let (kind, context, progress, error, quality, warning) = match rx.recv().await {
+                Ok(FFMpegEvent::START(context)) => {
+                    (JobEventKind::Start, context, None, None, None, None)
+                }
+                Ok(FFMpegEvent::PROGRESS(context, progress)) => {
+                    let progress = Self::get_progress(&context, &progress);
+                    if let Some(running_job) = running.lock().await.get_mut(&job_id) {
+                        running_job.progress = Some(progress.clone());
+                    }
+                    (
+                        JobEventKind::Progress,
+                        context,
+                        Some(progress),
+                        None,
+                        None,
+                        None,
+                    )
+                }
+                Ok(FFMpegEvent::DONE(context, quality)) => (
+                    JobEventKind::Done,
+                    context,
+                    None,
+                    None,
+                    quality.map(|quality| JobQuality {
+                        metric: quality.metric.name().to_lowercase(),
+                        score: quality.score,
+                        samples: quality.samples,
+                    }),
+                    None,
+                ),
+                Ok(FFMpegEvent::ERROR(context, failure)) => (
+                    JobEventKind::Error,
+                    context,
+                    None,
+                    Some(JobFailure {
+                        message: failure.message,
+                        stderr: failure.stderr,
+                    }),
+                    None,
+                    None,
+                ),
+                Ok(FFMpegEvent::WARNING(context, message)) => {
+                    warn!("Job #{}: {}", job_id, message);
+                    (
+                        JobEventKind::Warning,
+                        context,
+                        None,
+                        None,
+                        None,
+                        Some(message),
+                    )
+                }
+                Ok(FFMpegEvent::CANCELLED(context)) => {
+                    (JobEventKind::Cancelled, context, None, None, None, None)
+                }
+                Ok(FFMpegEvent::CLOSE()) | Err(RecvError::Closed) => break,
+                Err(RecvError::Lagged(_)) => continue,
+            };
+            let _ = events.send(JobEvent {
+                job_id,
+                kind,
+                input_path: context.input_path,
+                output_path: context.output_path,
+                progress,
+                error,
+                quality,
+                warning,
+            });
+        }
+    }
+
+    async fn run_task(
+        &self,
+        job_id: u64,
+        input_path: &Path,
+        output_path: &Path,
+        cancel_token: FFMpegCancelToken,
+    ) -> lib::error::Result<()> {
+        info!(
+            "Transcoding job #{}: {:?} to {:?}",
+            job_id, input_path, output_path
+        );
+
+        let probe = ffprobe(input_path).await?;
+        let mut ffmpeg = FFMpeg::new(&self.args.config.ffmpeg, &self.profile);
+        ffmpeg.set_cancel_token(cancel_token);
         let mut join_set = JoinSet::new();
 
+        let rx = ffmpeg.subscribe();
+        let running = self.running.clone();
+        let events = self.events.clone();
+        join_set.spawn(Self::relay_events(job_id, running, events, rx));
+
         if let Some(webhook_url) = &self.args.config.discord.webhook_url {
-            let webhook = DiscordWebhook::new(&webhook_url);
+            let webhook = DiscordWebhook::new(webhook_url);
             let mut discord_handler = DiscordEventHandler::new(webhook);
             let rx = ffmpeg.subscribe();
             join_set.spawn(async move {
-                discord_handler.listen(rx).await;
+                if let Err(e) = discord_handler.listen(rx).await {
+                    error!("Failed to notify Discord of job #{}: {}", job_id, e);
+                }
             });
         }
 
-        let mut _guard = self.mutex.lock().await;
+        let result = ffmpeg.transcode(&probe, output_path).await.map(|_| ());
 
-        if let Err(e) = ffmpeg.transcode(&probe, output_path).await {
-            error!("An error happened while transcoding {}", e);
-        }
+        ffmpeg.dispose();
 
         join_set.join_all().await;
+
+        result
     }
 }
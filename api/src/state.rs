@@ -1,7 +1,7 @@
 use std::sync::Arc;
 
 use clap::Parser;
-use lib::config::Config;
+use lib::{config::Config, profile::Profile};
 
 use crate::services::task::TaskService;
 
@@ -11,6 +11,24 @@ pub struct AppArgs {
     #[arg(long, env = "ROOT_FOLDER_PATH", default_value = ".")]
     pub root_folder_path: String,
 
+    /// Folder holding the job journal, it must outlive the process for jobs
+    /// to be resumed, e.g. a mounted volume in Docker
+    #[arg(long, env = "DATA_DIR", default_value = "data")]
+    pub data_dir: String,
+
+    #[arg(
+        long,
+        env = "MAX_CONCURRENT_JOBS",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    pub max_concurrent_jobs: u16,
+
+    /// Done, failed and cancelled jobs kept in the journal, the oldest ones
+    /// are dropped past it
+    #[arg(long, env = "MAX_FINISHED_JOBS", default_value_t = 100)]
+    pub max_finished_jobs: usize,
+
     #[command(flatten)]
     pub config: Config,
 }
@@ -18,5 +36,6 @@ pub struct AppArgs {
 #[derive(Clone)]
 pub struct AppState {
     pub args: Arc<AppArgs>,
+    pub profile: Arc<Profile>,
     pub task_service: Arc<TaskService>,
 }
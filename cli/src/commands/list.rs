@@ -1,6 +1,22 @@
-use clap::Args;
+use clap::{Args, ValueEnum};
 use colored::Colorize;
-use lib::{config::Config, ffmpeg::FFMpeg, ffprobe::ffprobe, list_movie_files};
+use lib::{
+    config::Config,
+    ffmpeg::FFMpeg,
+    ffprobe::{FFProbeResultStream, ffprobe},
+    list_movie_files,
+    profile::Profile,
+    validation::ValidationIssue,
+};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum ListFormat {
+    Table,
+    Json,
+    Csv,
+}
 
 #[derive(Args)]
 pub struct ListArgs {
@@ -9,30 +25,271 @@ pub struct ListArgs {
     #[arg(short, long)]
     recursive: bool,
 
+    #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+    format: ListFormat,
+
     #[command(flatten)]
     config: Config,
 }
 
-pub async fn cmd_list(args: &ListArgs) {
-    let resolved = std::path::Path::new(args.path.as_deref().unwrap_or("."));
+#[derive(Serialize)]
+struct ListStream {
+    index: u8,
+    codec_type: String,
+    codec_name: Option<String>,
+    channels: Option<u8>,
+    bit_rate: Option<u64>,
+    width: Option<u32>,
+    height: Option<u32>,
+    language: String,
+}
+
+#[derive(Serialize)]
+struct ListEntry {
+    path: String,
+    valid: bool,
+    container: Option<String>,
+    duration: Option<f64>,
+    size: Option<u64>,
+    streams: Vec<ListStream>,
+    reasons: Vec<ValidationIssue>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+struct ListSummary {
+    files: usize,
+    valid: usize,
+    invalid: usize,
+    errors: usize,
+    size: u64,
+    duration: f64,
+}
+
+#[derive(Serialize)]
+struct ListOutput {
+    files: Vec<ListEntry>,
+    summary: ListSummary,
+}
+
+impl From<&FFProbeResultStream> for ListStream {
+    fn from(stream: &FFProbeResultStream) -> Self {
+        Self {
+            index: stream.index,
+            codec_type: stream.codec_type.clone(),
+            codec_name: stream.codec_name.clone(),
+            channels: stream.channels,
+            bit_rate: stream.bit_rate.as_ref().and_then(|b| b.parse().ok()),
+            width: stream.width,
+            height: stream.height,
+            language: stream.language().into(),
+        }
+    }
+}
+
+async fn get_entry(path: &Path, ffmpeg: &FFMpeg) -> ListEntry {
+    let path_name = path.display().to_string();
+    match ffprobe(path).await {
+        Ok(probe) => {
+            let report = ffmpeg.validate(&probe);
+            ListEntry {
+                path: path_name,
+                valid: report.is_valid(),
+                container: Some(probe.format.format_name.clone()),
+                duration: probe.format.duration.parse().ok(),
+                size: probe.format.size.as_ref().and_then(|s| s.parse().ok()),
+                streams: probe.streams.iter().map(ListStream::from).collect(),
+                reasons: report.issues,
+                error: None,
+            }
+        }
+        Err(e) => ListEntry {
+            path: path_name,
+            valid: false,
+            container: None,
+            duration: None,
+            size: None,
+            streams: vec![],
+            reasons: vec![],
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+fn get_summary(entries: &[ListEntry]) -> ListSummary {
+    let mut summary = ListSummary::default();
+    for entry in entries {
+        summary.files += 1;
+        if entry.error.is_some() {
+            summary.errors += 1;
+        } else if entry.valid {
+            summary.valid += 1;
+        } else {
+            summary.invalid += 1;
+        }
+        summary.size += entry.size.unwrap_or(0);
+        summary.duration += entry.duration.unwrap_or(0.0);
+    }
+    summary
+}
 
-    let ffmpeg = FFMpeg::new(&args.config.ffmpeg);
+fn format_size(size: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
 
-    if let Ok(entries) = list_movie_files(&resolved, &args.recursive).await {
-        for entry in entries {
-            let probe = ffprobe(&entry).await.unwrap();
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
+fn print_table(output: &ListOutput) {
+    for entry in output.files.iter() {
+        let status = if entry.error.is_some() {
+            "ERROR".red()
+        } else if entry.valid {
+            "VALID".green()
+        } else {
+            "INVALID".red()
+        };
+        println!("{} {}", status.bold(), entry.path.yellow());
+
+        if let Some(error) = &entry.error {
+            println!("  {}", error.red());
+            continue;
+        }
+
+        println!(
+            "  {}, {}, {}",
+            entry.container.as_deref().unwrap_or("unknown"),
+            format_duration(entry.duration.unwrap_or(0.0)),
+            format_size(entry.size.unwrap_or(0))
+        );
+        for stream in entry.streams.iter() {
+            let mut details = vec![stream.language.clone()];
+            if let (Some(width), Some(height)) = (stream.width, stream.height) {
+                details.push(format!("{}x{}", width, height));
+            }
+            if let Some(channels) = stream.channels {
+                details.push(format!("{} channels", channels));
+            }
+            if let Some(bit_rate) = stream.bit_rate {
+                details.push(format!("{} kb/s", bit_rate / 1000));
+            }
             println!(
-                "Found: {}, valid: {}",
-                entry.to_str().unwrap().yellow(),
-                (if ffmpeg.is_valid(&probe) {
-                    "TRUE".green()
-                } else {
-                    "FALSE".red()
-                })
-                .bold()
+                "  #{} {} {} ({})",
+                stream.index,
+                stream.codec_type,
+                stream.codec_name.as_deref().unwrap_or("unknown"),
+                details.join(", ")
             );
         }
+        for reason in entry.reasons.iter() {
+            println!("  - {}", reason.to_string().red());
+        }
+    }
+
+    let summary = &output.summary;
+    println!(
+        "{} files: {} valid, {} invalid, {} errors, {}, {}",
+        summary.files,
+        summary.valid.to_string().green(),
+        summary.invalid.to_string().red(),
+        summary.errors.to_string().red(),
+        format_size(summary.size),
+        format_duration(summary.duration)
+    );
+}
+
+fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
     } else {
-        println!("Failed to read path: {}", resolved.to_str().unwrap().red())
+        value.into()
     }
 }
+
+fn print_csv(output: &ListOutput) {
+    println!("path,valid,container,duration,size,streams,reasons,error");
+    for entry in output.files.iter() {
+        let streams = entry
+            .streams
+            .iter()
+            .map(|stream| {
+                let mut parts = vec![
+                    stream.codec_type.clone(),
+                    stream.codec_name.clone().unwrap_or_default(),
+                    stream.language.clone(),
+                ];
+                if let (Some(width), Some(height)) = (stream.width, stream.height) {
+                    parts.push(format!("{}x{}", width, height));
+                }
+                if let Some(channels) = stream.channels {
+                    parts.push(format!("{}ch", channels));
+                }
+                if let Some(bit_rate) = stream.bit_rate {
+                    parts.push(bit_rate.to_string());
+                }
+                parts.join(":")
+            })
+            .collect::<Vec<_>>()
+            .join(";");
+        let row = [
+            entry.path.clone(),
+            entry.valid.to_string(),
+            entry.container.clone().unwrap_or_default(),
+            entry.duration.map(|d| d.to_string()).unwrap_or_default(),
+            entry.size.map(|s| s.to_string()).unwrap_or_default(),
+            streams,
+            entry
+                .reasons
+                .iter()
+                .map(|reason| reason.to_string())
+                .collect::<Vec<_>>()
+                .join("; "),
+            entry.error.clone().unwrap_or_default(),
+        ];
+        println!(
+            "{}",
+            row.iter()
+                .map(|value| escape_csv(value))
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+    }
+}
+
+pub async fn cmd_list(args: &ListArgs) -> anyhow::Result<()> {
+    let resolved = Path::new(args.path.as_deref().unwrap_or("."));
+
+    let profile = Profile::load(&args.config.ffmpeg).await?;
+
+    let ffmpeg = FFMpeg::new(&args.config.ffmpeg, &profile);
+
+    let mut files = vec![];
+    for entry in list_movie_files(resolved, &args.recursive, &args.config.scan).await? {
+        files.push(get_entry(&entry, &ffmpeg).await);
+    }
+
+    let summary = get_summary(&files);
+    let output = ListOutput { files, summary };
+
+    match args.format {
+        ListFormat::Table => print_table(&output),
+        ListFormat::Json => println!("{}", serde_json::to_string_pretty(&output)?),
+        ListFormat::Csv => print_csv(&output),
+    }
+
+    Ok(())
+}
@@ -1,12 +1,15 @@
 use anyhow::anyhow;
 use clap::Args;
+use colored::Colorize;
 use lib::{
-    config::Config,
+    clean_tmp_files,
+    config::{Config, QualityMetric},
     discord::{DiscordEventHandler, DiscordWebhook},
-    ffmpeg::FFMpeg,
+    ffmpeg::{FFMpeg, FFMpegLoudness, FFMpegQuality, FFMpegStreamAction},
     ffprobe::ffprobe,
     list_movie_files,
     log::LogEventHandler,
+    profile::Profile,
     utils::get_output_file_name,
 };
 use regex;
@@ -26,6 +29,10 @@ pub struct TranscodeArgs {
     #[arg(short, long)]
     force: bool,
 
+    /// Print what would be done to each file without running ffmpeg
+    #[arg(long)]
+    dry_run: bool,
+
     #[command(flatten)]
     config: Config,
 }
@@ -33,7 +40,48 @@ pub struct TranscodeArgs {
 pub async fn cmd_transcode(args: &TranscodeArgs) -> anyhow::Result<()> {
     let input_path = fs::canonicalize(Path::new(&args.path)).await?;
 
-    let mut ffmpeg = FFMpeg::new(&args.config.ffmpeg);
+    let profile = Profile::load(&args.config.ffmpeg).await?;
+
+    let mut ffmpeg = FFMpeg::new(&args.config.ffmpeg, &profile);
+
+    let metadata = fs::metadata(&input_path).await?;
+
+    if args.dry_run {
+        if metadata.is_file() {
+            let output_path = match args.out.as_ref().map(PathBuf::from) {
+                Some(path) => path,
+                None => get_output_path(&input_path, &profile).await?,
+            };
+            plan_file(&input_path, &output_path, &ffmpeg, args.force).await?;
+        } else if metadata.is_dir() {
+            for entry in list_movie_files(&input_path, &args.recursive, &args.config.scan).await? {
+                let output_path = get_output_path(&entry, &profile).await?;
+                plan_file(&entry, &output_path, &ffmpeg, args.force).await?;
+            }
+        }
+        return Ok(());
+    }
+
+    let mut clean_roots = match metadata.is_dir() {
+        true => vec![(input_path.clone(), args.recursive)],
+        false => input_path
+            .parent()
+            .map(|parent| vec![(parent.to_path_buf(), false)])
+            .unwrap_or_default(),
+    };
+    if let Some(scratch_dir) = &args.config.ffmpeg.scratch_dir {
+        clean_roots.push((PathBuf::from(scratch_dir), false));
+    }
+    for (root, recursive) in clean_roots {
+        let max_age = args.config.ffmpeg.stale_tmp_age();
+        let cleaned = clean_tmp_files(&root, &recursive, max_age, &profile).await;
+        for path in cleaned.removed {
+            println!("Removed stale temporary file {}", path.display());
+        }
+        for e in cleaned.errors {
+            println!("{}", e.to_string().yellow());
+        }
+    }
 
     let mut join_set = JoinSet::new();
 
@@ -47,22 +95,31 @@ pub async fn cmd_transcode(args: &TranscodeArgs) -> anyhow::Result<()> {
         let mut discord_handler = DiscordEventHandler::new(DiscordWebhook::new(webhook_url));
         let rx = ffmpeg.subscribe();
         join_set.spawn(async move {
-            discord_handler.listen(rx).await;
+            if let Err(e) = discord_handler.listen(rx).await {
+                println!("{}", e.to_string().red());
+            }
         });
     }
 
-    let metadata = fs::metadata(&input_path).await?;
-
+    let mut qualities = vec![];
     if metadata.is_file() {
-        let output_path = match args.out.as_ref().map(|p| PathBuf::from(p)) {
+        let output_path = match args.out.as_ref().map(PathBuf::from) {
             Some(path) => path,
-            None => get_output_path(&input_path).await?,
+            None => get_output_path(&input_path, &profile).await?,
         };
-        transcode_file(&input_path, &output_path, &mut ffmpeg, args.force).await?;
+        if let Some(quality) =
+            transcode_file(&input_path, &output_path, &mut ffmpeg, args.force).await?
+        {
+            qualities.push((output_path, quality));
+        }
     } else if metadata.is_dir() {
-        for entry in list_movie_files(&input_path, &args.recursive).await? {
-            let output_path = get_output_path(&entry).await?;
-            transcode_file(&entry, &output_path, &mut ffmpeg, args.force).await?;
+        for entry in list_movie_files(&input_path, &args.recursive, &args.config.scan).await? {
+            let output_path = get_output_path(&entry, &profile).await?;
+            if let Some(quality) =
+                transcode_file(&entry, &output_path, &mut ffmpeg, args.force).await?
+            {
+                qualities.push((output_path, quality));
+            }
         }
     }
 
@@ -70,10 +127,17 @@ pub async fn cmd_transcode(args: &TranscodeArgs) -> anyhow::Result<()> {
 
     join_set.join_all().await;
 
+    if !qualities.is_empty() {
+        println!("{}", "Quality".bold());
+        for (output_path, quality) in qualities {
+            println!("  {}: {}", output_path.display(), quality);
+        }
+    }
+
     Ok(())
 }
 
-async fn get_output_path(input_path: &Path) -> anyhow::Result<PathBuf> {
+async fn get_output_path(input_path: &Path, profile: &Profile) -> anyhow::Result<PathBuf> {
     let input_name = input_path
         .file_name()
         .and_then(|s| s.to_str())
@@ -115,7 +179,7 @@ async fn get_output_path(input_path: &Path) -> anyhow::Result<PathBuf> {
         name = format!("{} {}", name, year.as_str());
     }
 
-    Ok(folder_path.join(&get_output_file_name(&name)))
+    Ok(folder_path.join(get_output_file_name(&name, profile)))
 }
 
 async fn transcode_file(
@@ -123,10 +187,149 @@ async fn transcode_file(
     output_path: &Path,
     ffmpeg: &mut FFMpeg,
     force: bool,
+) -> anyhow::Result<Option<FFMpegQuality>> {
+    let probe = ffprobe(input_path).await?;
+    let report = ffmpeg.validate(&probe);
+    if force || !report.is_valid() {
+        if !report.is_valid() {
+            println!("{}", input_path.display().to_string().yellow().bold());
+            for message in report.messages() {
+                println!("  - {}", message.red());
+            }
+        }
+        return Ok(ffmpeg.transcode(&probe, output_path).await?);
+    }
+    Ok(None)
+}
+
+async fn plan_file(
+    input_path: &Path,
+    output_path: &Path,
+    ffmpeg: &FFMpeg,
+    force: bool,
 ) -> anyhow::Result<()> {
     let probe = ffprobe(input_path).await?;
-    if force || !ffmpeg.is_valid(&probe) {
-        ffmpeg.transcode(&probe, output_path).await?
+    let report = ffmpeg.validate(&probe);
+    let is_valid = report.is_valid();
+
+    println!("{}", input_path.display().to_string().yellow().bold());
+    println!("  Output: {}", output_path.display());
+
+    if is_valid && !force {
+        println!("  {}", "Already valid, skipped".green());
+        return Ok(());
+    }
+    println!(
+        "  {}",
+        if is_valid {
+            "Already valid, forced".yellow()
+        } else {
+            "Needs transcoding".red()
+        }
+    );
+    for message in report.messages() {
+        println!("    - {}", message.red());
+    }
+
+    println!("  Streams:");
+    for (index, action) in ffmpeg.get_stream_actions(&probe, output_path) {
+        let stream = probe
+            .streams
+            .iter()
+            .find(|stream| stream.index == index)
+            .ok_or(anyhow!("Unknown stream {}", index))?;
+        let description = match action {
+            FFMpegStreamAction::Copy => "copy".green(),
+            FFMpegStreamAction::Encode(codec) => format!("encode to {}", codec).yellow(),
+            FFMpegStreamAction::Downmix(codec, channels) => {
+                format!("downmix to {} {} channels", codec, channels).yellow()
+            }
+            FFMpegStreamAction::Burn => "burn into video".yellow(),
+            FFMpegStreamAction::Extract(path) => format!("extract to {}", path.display()).cyan(),
+            FFMpegStreamAction::Drop => "drop".red(),
+        };
+        println!(
+            "    #{} {} {} ({}): {}",
+            index,
+            stream.codec_type,
+            stream.codec_name.as_deref().unwrap_or("unknown"),
+            stream.language(),
+            description
+        );
+    }
+
+    if let Some(bitrate) = ffmpeg.get_target_video_bitrate(&probe) {
+        println!(
+            "  Video bitrate: {} kb/s{}",
+            bitrate / 1000,
+            if ffmpeg.is_two_pass(&probe) {
+                ", two passes"
+            } else {
+                ""
+            }
+        );
+    }
+
+    if let Err(error) = ffmpeg.check_free_space(&probe, output_path) {
+        println!("  {}", error.to_string().red());
+    }
+
+    if ffmpeg.config.loudnorm {
+        println!("  Loudness: measured in a first pass before encoding");
+    }
+
+    if let Some(metric) = ffmpeg.config.quality_metric {
+        let segments = ffmpeg.get_quality_segments(&probe, ffmpeg.config.quality_samples);
+        match segments.iter().flatten().count() {
+            0 => println!("  Quality: {} over the whole video", metric.name()),
+            samples => println!(
+                "  Quality: {} over {} samples of {}s",
+                metric.name(),
+                samples,
+                ffmpeg.config.quality_sample_seconds
+            ),
+        }
     }
+
+    if let Some(target) = ffmpeg.config.target_quality
+        && ffmpeg.get_target_video_bitrate(&probe).is_none()
+    {
+        let metric = ffmpeg.config.quality_metric.unwrap_or(QualityMetric::Vmaf);
+        let (low, high) = ffmpeg.get_crf_search_range();
+        println!(
+            "  CRF: highest from {} to {} reaching {} {} over {} samples, {} otherwise",
+            low,
+            high,
+            metric.name(),
+            target,
+            ffmpeg.get_crf_search_segments(&probe).len(),
+            ffmpeg.get_crf_level()
+        );
+    }
+
+    for cmd in ffmpeg.get_commands(&probe, output_path, &FFMpegLoudness::new(), None) {
+        println!("  Command: {}", FFMpeg::format_command(&cmd));
+    }
+
+    let keep_input_file = ffmpeg.config.keep_input_file;
+    if !keep_input_file && input_path != output_path {
+        println!("  Input file: {}", "deleted".red());
+    } else {
+        println!("  Input file: {}", "kept".green());
+    }
+
+    for (entry_path, target_path) in FFMpeg::get_srt_files(input_path, output_path).await? {
+        println!(
+            "  Sidecar: {} {} {}",
+            entry_path.display(),
+            if keep_input_file {
+                "copied to"
+            } else {
+                "moved to"
+            },
+            target_path.display()
+        );
+    }
+
     Ok(())
 }
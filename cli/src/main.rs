@@ -27,10 +27,13 @@ async fn main() -> ExitCode {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::List(args) => {
-            cmd_list(args).await;
-            ExitCode::SUCCESS
-        }
+        Commands::List(args) => match cmd_list(args).await {
+            Ok(_) => ExitCode::SUCCESS,
+            Err(e) => {
+                println!("Error: {}", e);
+                ExitCode::FAILURE
+            }
+        },
         Commands::Transcode(args) => match cmd_transcode(args).await {
             Ok(_) => ExitCode::SUCCESS,
             Err(e) => {
@@ -0,0 +1,189 @@
+/// Video codecs with a known software encoder and rate control mapping.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VideoCodec {
+    H264,
+    Hevc,
+    Av1,
+}
+
+impl VideoCodec {
+    /// Parses a codec name as used in profiles or reported by ffprobe.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "h264" | "avc" | "x264" | "libx264" => Some(Self::H264),
+            "hevc" | "h265" | "x265" | "libx265" => Some(Self::Hevc),
+            "av1" | "libsvtav1" => Some(Self::Av1),
+            _ => None,
+        }
+    }
+
+    /// Name of the codec as reported by ffprobe.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264",
+            Self::Hevc => "hevc",
+            Self::Av1 => "av1",
+        }
+    }
+
+    pub fn encoder(&self) -> &'static str {
+        match self {
+            Self::H264 => "libx264",
+            Self::Hevc => "libx265",
+            Self::Av1 => "libsvtav1",
+        }
+    }
+
+    /// CRF giving roughly the same quality as libx264's default of 23.
+    pub fn default_crf(&self) -> u8 {
+        match self {
+            Self::H264 => 23,
+            Self::Hevc => 28,
+            Self::Av1 => 35,
+        }
+    }
+
+    /// CRFs the quality search picks from, lower ones make huge files for
+    /// no visible gain and higher ones visibly degrade the video.
+    pub fn crf_search_range(&self) -> (u8, u8) {
+        match self {
+            Self::H264 => (16, 32),
+            Self::Hevc => (20, 36),
+            Self::Av1 => (20, 50),
+        }
+    }
+
+    pub fn max_crf(&self) -> u8 {
+        match self {
+            Self::H264 | Self::Hevc => 51,
+            Self::Av1 => 63,
+        }
+    }
+
+    /// Translates an x264 style preset name to the encoder's own scale,
+    /// SVT-AV1 uses numeric presets where lower is slower.
+    pub fn preset(&self, preset: &str) -> String {
+        match self {
+            Self::H264 | Self::Hevc => preset.into(),
+            Self::Av1 => match preset {
+                "ultrafast" => "12",
+                "superfast" => "11",
+                "veryfast" => "10",
+                "faster" => "9",
+                "fast" => "8",
+                "medium" => "7",
+                "slow" => "5",
+                "slower" => "4",
+                "veryslow" | "placebo" => "2",
+                other => other,
+            }
+            .into(),
+        }
+    }
+
+    /// Codec tag to set when muxing into mp4 so Apple players accept it.
+    pub fn mp4_tag(&self) -> Option<&'static str> {
+        match self {
+            Self::Hevc => Some("hvc1"),
+            _ => None,
+        }
+    }
+}
+
+/// Most channels an audio encoder accepts, `None` when it has no limit we
+/// know of. AC-3 and E-AC-3 stop at 5.1.
+pub fn max_audio_channels(codec: &str) -> Option<u8> {
+    match codec.to_ascii_lowercase().as_str() {
+        "ac3" | "eac3" => Some(6),
+        _ => None,
+    }
+}
+
+/// H.264 levels, as reported by ffprobe (ten times the level number), with
+/// their max macroblocks per second, macroblocks per frame and Main profile
+/// bitrate in kb/s. Levels below 3.0 are left out, nothing we encode is that
+/// small.
+const H264_LEVELS: [(u8, u64, u64, u64); 10] = [
+    (30, 40_500, 1_620, 10_000),
+    (31, 108_000, 3_600, 14_000),
+    (32, 216_000, 5_120, 20_000),
+    (40, 245_760, 8_192, 20_000),
+    (41, 245_760, 8_192, 50_000),
+    (42, 522_240, 8_704, 50_000),
+    (50, 589_824, 22_080, 135_000),
+    (51, 983_040, 36_864, 240_000),
+    (52, 2_073_600, 36_864, 240_000),
+    (60, 4_177_920, 139_264, 240_000),
+];
+
+/// Lowest H.264 level allowing the given resolution, frame rate and bitrate
+/// in the given x264 profile.
+pub fn h264_level(width: u32, height: u32, frame_rate: f64, max_bitrate: u32, profile: &str) -> u8 {
+    let frame_size = (width as u64).div_ceil(16) * (height as u64).div_ceil(16);
+    let macroblock_rate = (frame_size as f64 * frame_rate).ceil() as u64;
+    let bitrate_factor = match profile {
+        "high" => 1.25,
+        "high10" => 3.0,
+        _ => 1.0,
+    };
+    let max_bitrate_kbps = max_bitrate as f64 / 1000.0 / bitrate_factor;
+    H264_LEVELS
+        .iter()
+        .find(
+            |(_, level_macroblock_rate, level_frame_size, level_bitrate)| {
+                macroblock_rate <= *level_macroblock_rate
+                    && frame_size <= *level_frame_size
+                    && max_bitrate_kbps <= *level_bitrate as f64
+            },
+        )
+        .unwrap_or(&H264_LEVELS[H264_LEVELS.len() - 1])
+        .0
+}
+
+/// Formats a level as reported by ffprobe, `41` becoming `4.1`.
+pub fn format_h264_level(level: u8) -> String {
+    format!("{}.{}", level / 10, level % 10)
+}
+
+/// Parses a level written like `4.1` to the way ffprobe reports it.
+pub fn parse_h264_level(value: &str) -> Result<u8, String> {
+    let (major, minor) = value.split_once('.').unwrap_or((value, "0"));
+    match (major.parse::<u8>(), minor.parse::<u8>()) {
+        (Ok(major @ 1..=6), Ok(minor @ 0..=9)) => Ok(major * 10 + minor),
+        _ => Err(format!("{} is not an H.264 level", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn h264_level_fits_resolution_and_frame_rate() {
+        assert_eq!(h264_level(1280, 720, 30.0, 4_000_000, "high"), 31);
+        assert_eq!(h264_level(1920, 1080, 24.0, 4_000_000, "high"), 40);
+        assert_eq!(h264_level(1920, 1080, 60.0, 4_000_000, "high"), 42);
+        assert_eq!(h264_level(3840, 2160, 24.0, 4_000_000, "high"), 51);
+        assert_eq!(h264_level(3840, 2160, 60.0, 4_000_000, "high"), 52);
+    }
+
+    #[test]
+    fn h264_level_fits_bitrate() {
+        assert_eq!(h264_level(1920, 1080, 24.0, 25_000_000, "high"), 40);
+        assert_eq!(h264_level(1920, 1080, 24.0, 30_000_000, "high"), 41);
+        assert_eq!(h264_level(1920, 1080, 24.0, 25_000_000, "main"), 41);
+    }
+
+    #[test]
+    fn h264_level_is_capped() {
+        assert_eq!(h264_level(7680, 4320, 60.0, 4_000_000, "high"), 60);
+    }
+
+    #[test]
+    fn h264_level_round_trips() {
+        assert_eq!(parse_h264_level("4.1"), Ok(41));
+        assert_eq!(parse_h264_level("5"), Ok(50));
+        assert!(parse_h264_level("4.x").is_err());
+        assert_eq!(format_h264_level(41), "4.1");
+    }
+}
@@ -1,4 +1,8 @@
-use clap::Parser;
+use std::time::Duration;
+
+use clap::{Parser, ValueEnum};
+
+use crate::{codec::parse_h264_level, profile::DEFAULT_PROFILE_NAME};
 
 #[derive(Parser, Debug, Clone)]
 pub struct DiscordConfig {
@@ -6,14 +10,44 @@ pub struct DiscordConfig {
     pub webhook_url: Option<String>,
 }
 
+/// What to do with bitmap subtitles, which can't be muxed as text.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageSubtitleMode {
+    Drop,
+    /// Write them to sidecar files next to the output
+    Extract,
+    /// Burn the forced track into the video
+    Burn,
+}
+
+/// Metric comparing the encoded video to the input.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QualityMetric {
+    /// Requires ffmpeg built with libvmaf
+    Vmaf,
+    Ssim,
+    Psnr,
+}
+
+impl QualityMetric {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Vmaf => "VMAF",
+            Self::Ssim => "SSIM",
+            Self::Psnr => "PSNR",
+        }
+    }
+}
+
 #[derive(Parser, Debug, Clone)]
 pub struct FFMpegConfig {
-    #[arg(
-        long = "ffmpeg-crf-level",
-        env = "FFMPEG_CRF_LEVEL",
-        default_value_t = 23
-    )]
-    pub crf_level: u8,
+    /// Defaults to a value suited to the profile's video codec
+    #[arg(long = "ffmpeg-crf-level", env = "FFMPEG_CRF_LEVEL")]
+    pub crf_level: Option<u8>,
+
+    /// Encoder preset, x264 names are mapped to SVT-AV1's numeric presets
+    #[arg(long = "ffmpeg-preset", env = "FFMPEG_PRESET")]
+    pub preset: Option<String>,
 
     #[arg(
         long = "ffmpeg-video-maxrate",
@@ -22,6 +56,37 @@ pub struct FFMpegConfig {
     )]
     pub video_maxrate: u32,
 
+    /// H.264 videos above this level, and above the level of the encode,
+    /// are re-encoded. 4.1 is what most players and Blu-rays use
+    #[arg(
+        long = "ffmpeg-h264-max-level",
+        env = "FFMPEG_H264_MAX_LEVEL",
+        default_value = "4.1",
+        value_parser = parse_h264_level
+    )]
+    pub h264_max_level: u8,
+
+    /// Videos wider than this are downscaled, keeping their aspect ratio
+    #[arg(long = "ffmpeg-max-width", env = "FFMPEG_MAX_WIDTH")]
+    pub max_width: Option<u32>,
+
+    /// Videos taller than this are downscaled, keeping their aspect ratio
+    #[arg(long = "ffmpeg-max-height", env = "FFMPEG_MAX_HEIGHT")]
+    pub max_height: Option<u32>,
+
+    /// Encode the video at this bitrate instead of a CRF, in two passes for H.264
+    #[arg(
+        long = "ffmpeg-target-bitrate",
+        env = "FFMPEG_TARGET_BITRATE",
+        conflicts_with = "target_size"
+    )]
+    pub target_bitrate: Option<u32>,
+
+    /// Output size in bytes, the video bitrate is derived from the duration
+    /// and the audio bitrates
+    #[arg(long = "ffmpeg-target-size", env = "FFMPEG_TARGET_SIZE")]
+    pub target_size: Option<u64>,
+
     #[arg(
         long = "ffmpeg-audio-bitrate",
         env = "FFMPEG_AUDIO_BITRATE",
@@ -29,8 +94,179 @@ pub struct FFMpegConfig {
     )]
     pub audio_bitrate: u32,
 
+    /// Normalize the loudness of encoded audio tracks to EBU R128, measuring
+    /// them in a first pass
+    #[arg(long = "ffmpeg-loudnorm", env = "FFMPEG_LOUDNORM")]
+    pub loudnorm: bool,
+
+    /// Integrated loudness to normalize to, in LUFS
+    #[arg(
+        long = "ffmpeg-loudnorm-target",
+        env = "FFMPEG_LOUDNORM_TARGET",
+        default_value_t = -23.0,
+        allow_hyphen_values = true
+    )]
+    pub loudnorm_target: f64,
+
+    /// Downmix 5.1 to stereo favouring the center channel, where dialog is
+    #[arg(long = "ffmpeg-dialog-boost", env = "FFMPEG_DIALOG_BOOST")]
+    pub dialog_boost: bool,
+
+    #[arg(
+        long = "ffmpeg-surround-bitrate",
+        env = "FFMPEG_SURROUND_BITRATE",
+        default_value_t = 640_000
+    )]
+    pub surround_bitrate: u32,
+
+    /// Audio languages to keep, all of them are kept when empty
+    #[arg(
+        long = "ffmpeg-audio-languages",
+        env = "FFMPEG_AUDIO_LANGUAGES",
+        value_delimiter = ','
+    )]
+    pub audio_languages: Vec<String>,
+
+    /// Subtitle languages to keep, all of them are kept when empty
+    #[arg(
+        long = "ffmpeg-subtitle-languages",
+        env = "FFMPEG_SUBTITLE_LANGUAGES",
+        value_delimiter = ','
+    )]
+    pub subtitle_languages: Vec<String>,
+
+    #[arg(long = "ffmpeg-drop-commentary", env = "FFMPEG_DROP_COMMENTARY")]
+    pub drop_commentary: bool,
+
+    #[arg(
+        long = "ffmpeg-default-audio-language",
+        env = "FFMPEG_DEFAULT_AUDIO_LANGUAGE"
+    )]
+    pub default_audio_language: Option<String>,
+
+    #[arg(
+        long = "ffmpeg-default-subtitle-language",
+        env = "FFMPEG_DEFAULT_SUBTITLE_LANGUAGE"
+    )]
+    pub default_subtitle_language: Option<String>,
+
+    #[arg(
+        long = "ffmpeg-image-subtitles",
+        env = "FFMPEG_IMAGE_SUBTITLES",
+        value_enum,
+        default_value_t = ImageSubtitleMode::Drop
+    )]
+    pub image_subtitles: ImageSubtitleMode,
+
+    /// Also write text subtitles to `.srt` files next to the output
+    #[arg(
+        long = "ffmpeg-extract-text-subtitles",
+        env = "FFMPEG_EXTRACT_TEXT_SUBTITLES"
+    )]
+    pub extract_text_subtitles: bool,
+
     #[arg(long = "ffmpeg-keep-input-file", env = "FFMPEG_KEEP_INPUT_FILE")]
     pub keep_input_file: bool,
+
+    /// Max difference in seconds between the input and output durations for
+    /// the output to be accepted
+    #[arg(
+        long = "ffmpeg-duration-tolerance",
+        env = "FFMPEG_DURATION_TOLERANCE",
+        default_value_t = 1.0
+    )]
+    pub duration_tolerance: f64,
+
+    /// Compare the encoded video to the input with this metric once done
+    #[arg(
+        long = "ffmpeg-quality-metric",
+        env = "FFMPEG_QUALITY_METRIC",
+        value_enum
+    )]
+    pub quality_metric: Option<QualityMetric>,
+
+    /// Number of segments spread over the video to measure the quality on,
+    /// the whole video is measured when 0
+    #[arg(
+        long = "ffmpeg-quality-samples",
+        env = "FFMPEG_QUALITY_SAMPLES",
+        default_value_t = 0
+    )]
+    pub quality_samples: u32,
+
+    /// Encode at the highest CRF whose samples reach this score with the
+    /// quality metric, VMAF when none is set
+    #[arg(
+        long = "ffmpeg-target-quality",
+        env = "FFMPEG_TARGET_QUALITY",
+        conflicts_with_all = ["target_bitrate", "target_size"]
+    )]
+    pub target_quality: Option<f64>,
+
+    /// Length of each quality sample in seconds
+    #[arg(
+        long = "ffmpeg-quality-sample-seconds",
+        env = "FFMPEG_QUALITY_SAMPLE_SECONDS",
+        default_value_t = 10
+    )]
+    pub quality_sample_seconds: u32,
+
+    /// Directory for temporary outputs, e.g. on a faster disk, instead of
+    /// next to the final outputs
+    #[arg(long = "ffmpeg-scratch-dir", env = "FFMPEG_SCRATCH_DIR")]
+    pub scratch_dir: Option<String>,
+
+    /// Temporary outputs untouched for this many hours were left over by an
+    /// interrupted run and get removed
+    #[arg(
+        long = "ffmpeg-stale-tmp-hours",
+        env = "FFMPEG_STALE_TMP_HOURS",
+        default_value_t = 24
+    )]
+    pub stale_tmp_hours: u64,
+
+    /// Number of lines ffmpeg printed last to keep when it fails
+    #[arg(
+        long = "ffmpeg-stderr-lines",
+        env = "FFMPEG_STDERR_LINES",
+        default_value_t = 20
+    )]
+    pub stderr_lines: usize,
+
+    #[arg(long = "ffmpeg-profiles-file", env = "FFMPEG_PROFILES_FILE")]
+    pub profiles_file: Option<String>,
+
+    #[arg(
+        long = "ffmpeg-profile",
+        env = "FFMPEG_PROFILE",
+        default_value = DEFAULT_PROFILE_NAME
+    )]
+    pub profile: String,
+}
+
+impl FFMpegConfig {
+    /// Age after which a temporary output is considered left over.
+    pub fn stale_tmp_age(&self) -> Duration {
+        Duration::from_secs(self.stale_tmp_hours * 3600)
+    }
+}
+
+pub const DEFAULT_EXTENSIONS: &[&str] = &["mp4", "mkv", "avi", "m4v", "mov", "ts", "webm", "wmv"];
+
+#[derive(Parser, Debug, Clone)]
+pub struct ScanConfig {
+    /// Extensions of the files to pick up when scanning folders
+    #[arg(
+        long = "scan-extensions",
+        env = "SCAN_EXTENSIONS",
+        value_delimiter = ',',
+        default_values_t = DEFAULT_EXTENSIONS.iter().map(|ext| ext.to_string())
+    )]
+    pub extensions: Vec<String>,
+
+    /// Probe files without an extension and keep the ones containing video
+    #[arg(long = "scan-probe-extensionless", env = "SCAN_PROBE_EXTENSIONLESS")]
+    pub probe_extensionless: bool,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -40,4 +276,7 @@ pub struct Config {
 
     #[command(flatten, next_help_heading = "FFMpeg")]
     pub ffmpeg: FFMpegConfig,
+
+    #[command(flatten, next_help_heading = "Scan")]
+    pub scan: ScanConfig,
 }
@@ -1,8 +1,46 @@
 use reqwest::{Method, Response};
 use serde::{Deserialize, Serialize};
-use tokio::{self, sync::broadcast::Receiver};
+use tokio::{
+    self,
+    sync::broadcast::{Receiver, error::RecvError},
+};
 
-use crate::ffmpeg::FFMpegEvent;
+use crate::{error::Result, ffmpeg::FFMpegEvent};
+
+/// Discord rejects field values longer than 1024 characters, keep some room
+/// for code fences and a note about what was left out.
+const FIELD_MAX_LENGTH: usize = 1000;
+
+/// Lines fitting in a field value, taken from the end when `from_end` is set,
+/// with the number of lines left out.
+fn fit_lines(lines: &[String], from_end: bool) -> (Vec<&str>, usize) {
+    let mut kept = vec![];
+    let mut length = 0;
+    let ordered: Vec<&String> = match from_end {
+        true => lines.iter().rev().collect(),
+        false => lines.iter().collect(),
+    };
+    for line in ordered {
+        length += line.chars().count() + 1;
+        if length > FIELD_MAX_LENGTH {
+            break;
+        }
+        kept.push(line.as_str());
+    }
+    if from_end {
+        kept.reverse();
+    }
+    let omitted = lines.len() - kept.len();
+    (kept, omitted)
+}
+
+/// Cuts a value to the max field length, marking the cut.
+fn truncate(value: &str) -> String {
+    match value.char_indices().nth(FIELD_MAX_LENGTH - 1) {
+        Some((end, _)) => format!("{}…", &value[..end]),
+        None => value.into(),
+    }
+}
 
 #[derive(Serialize)]
 pub struct DiscordEmbedField {
@@ -35,32 +73,31 @@ pub struct DiscordWebhook {
 }
 
 impl DiscordWebhook {
-    pub fn new(url: &String) -> DiscordWebhook {
-        DiscordWebhook { url: url.clone() }
+    pub fn new(url: &str) -> DiscordWebhook {
+        DiscordWebhook {
+            url: url.to_owned(),
+        }
     }
 
-    async fn fetch(&self, method: Method, path: &str, data: DiscordEmbed) -> Response {
+    async fn fetch(&self, method: Method, path: &str, data: DiscordEmbed) -> Result<Response> {
         let url = self.url.to_owned() + path;
-        reqwest::Client::new()
+        Ok(reqwest::Client::new()
             .request(method, url)
             .json(&DiscordWebhookData { embeds: vec![data] })
             .send()
-            .await
-            .expect("Discord fetch failed")
+            .await?
+            .error_for_status()?)
     }
 
-    async fn execute(&self, embed: DiscordEmbed) -> DiscordWebhookMessage {
-        let response = self.fetch(Method::POST, "?wait=true", embed).await;
+    async fn execute(&self, embed: DiscordEmbed) -> Result<DiscordWebhookMessage> {
+        let response = self.fetch(Method::POST, "?wait=true", embed).await?;
 
-        let data = response
-            .json::<DiscordWebhookResponse>()
-            .await
-            .expect("Invalid json response");
+        let data = response.json::<DiscordWebhookResponse>().await?;
 
-        DiscordWebhookMessage {
+        Ok(DiscordWebhookMessage {
             id: data.id,
             webhook: self.clone(),
-        }
+        })
     }
 }
 
@@ -71,14 +108,15 @@ pub struct DiscordWebhookMessage {
 }
 
 impl DiscordWebhookMessage {
-    async fn update(&self, embed: DiscordEmbed) {
+    async fn update(&self, embed: DiscordEmbed) -> Result<()> {
         self.webhook
             .fetch(
                 Method::PATCH,
                 format!("/messages/{}", self.id).as_str(),
                 embed,
             )
-            .await;
+            .await?;
+        Ok(())
     }
 }
 
@@ -105,7 +143,7 @@ impl DiscordEventHandler {
                         name: "Duration".into(),
                         value: format!(
                             "{:.0}s",
-                            context.probe.format.duration.parse::<f64>().unwrap()
+                            context.probe.format.duration_seconds().unwrap_or(0.0)
                         ),
                         inline: Some(true),
                     },
@@ -119,13 +157,46 @@ impl DiscordEventHandler {
                         value: progress.speed.clone(),
                         inline: Some(false),
                     },
+                    DiscordEmbedField {
+                        name: "Pass".into(),
+                        value: format!("{}/{}", progress.pass, progress.passes),
+                        inline: Some(true),
+                    },
                 ],
             ),
-            FFMpegEvent::DONE(context) => {
-                ("Transcoded file successfully", 0x22c55e, context, vec![])
+            FFMpegEvent::DONE(context, quality) => (
+                "Transcoded file successfully",
+                0x22c55e,
+                context,
+                quality
+                    .iter()
+                    .map(|quality| DiscordEmbedField {
+                        name: "Quality".into(),
+                        value: quality.to_string(),
+                        inline: Some(false),
+                    })
+                    .collect(),
+            ),
+            FFMpegEvent::ERROR(context, failure) => {
+                let mut fields = vec![DiscordEmbedField {
+                    name: "Error".into(),
+                    value: truncate(&failure.message),
+                    inline: Some(false),
+                }];
+                // The last lines tell what went wrong
+                let (lines, _) = fit_lines(&failure.stderr, true);
+                if !lines.is_empty() {
+                    let stderr = lines.join("\n");
+                    fields.push(DiscordEmbedField {
+                        name: "ffmpeg output".into(),
+                        value: format!("```\n{}\n```", stderr),
+                        inline: Some(false),
+                    });
+                }
+                ("An unexpected error happened", 0xef4444, context, fields)
             }
-            FFMpegEvent::ERROR(context) => {
-                ("An unexpected error happened", 0xef4444, context, vec![])
+            FFMpegEvent::CANCELLED(context) => {
+                ("Transcoding was cancelled", 0x6b7280, context, vec![])
             }
             _ => return None,
         };
@@ -143,11 +214,30 @@ impl DiscordEventHandler {
             },
         ];
 
+        if !context.report.is_valid() {
+            let messages = context
+                .report
+                .messages()
+                .iter()
+                .map(|message| format!("- {}", message))
+                .collect::<Vec<_>>();
+            let (mut lines, omitted) = fit_lines(&messages, false);
+            let more = format!("- and {} more", omitted);
+            if omitted > 0 {
+                lines.push(&more);
+            }
+            fields.push(DiscordEmbedField {
+                name: "Reasons".into(),
+                value: lines.join("\n"),
+                inline: Some(false),
+            });
+        }
+
         fields.append(&mut additional_fields);
 
         fields.push(DiscordEmbedField {
             name: "Command".into(),
-            value: format!("```shell\n{}\n```", context.command),
+            value: format!("```shell\n{}\n```", truncate(&context.command)),
             inline: Some(false),
         });
 
@@ -159,15 +249,31 @@ impl DiscordEventHandler {
         })
     }
 
-    pub async fn listen(&mut self, mut rx: Receiver<FFMpegEvent>) {
+    /// Posts a message per transcode and keeps it updated. Failed calls
+    /// don't stop the handler, the last failure is returned once closed.
+    pub async fn listen(&mut self, mut rx: Receiver<FFMpegEvent>) -> Result<()> {
         let mut message_opt: Option<DiscordWebhookMessage> = None;
+        let mut result = Ok(());
 
-        while let Ok(event) = rx.recv().await {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
             if let Some(embed) = Self::get_payload(&event) {
                 if let FFMpegEvent::START(_) = event {
-                    message_opt = Some(self.webhook.clone().execute(embed).await);
-                } else if let Some(message) = &message_opt {
-                    message.update(embed).await;
+                    match self.webhook.clone().execute(embed).await {
+                        Ok(message) => message_opt = Some(message),
+                        Err(e) => {
+                            message_opt = None;
+                            result = Err(e);
+                        }
+                    }
+                } else if let Some(message) = &message_opt
+                    && let Err(e) = message.update(embed).await
+                {
+                    result = Err(e);
                 }
             }
 
@@ -175,5 +281,7 @@ impl DiscordEventHandler {
                 break;
             }
         }
+
+        result
     }
 }
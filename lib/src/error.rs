@@ -0,0 +1,136 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub enum Error {
+    /// ffprobe failed or printed something we could not read.
+    Probe {
+        path: PathBuf,
+        reason: String,
+    },
+    /// ffmpeg exited unsuccessfully, with the end of what it printed.
+    Encoder {
+        code: Option<i32>,
+        stderr: Vec<String>,
+    },
+    /// loudnorm's analysis pass printed no usable measurement.
+    Loudness {
+        stream: u8,
+        reason: String,
+    },
+    Cancelled,
+    /// The quality filter printed no usable score.
+    Quality(String),
+    /// The output does not match what was asked of ffmpeg.
+    Verification(Vec<String>),
+    /// Paths are passed around as strings and must be valid UTF-8.
+    InvalidPath(PathBuf),
+    Profile(String),
+    NotEnoughSpace {
+        path: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+    /// A Discord webhook call failed.
+    Notifier(reqwest::Error),
+    /// A filesystem step failed, `step` describing what was done to `path`.
+    Io {
+        step: &'static str,
+        path: PathBuf,
+        source: io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Last lines ffmpeg printed before failing.
+    pub fn stderr(&self) -> &[String] {
+        match self {
+            Self::Encoder { stderr, .. } => stderr,
+            _ => &[],
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Probe { path, reason } => {
+                write!(f, "Failed to probe {}: {}", path.display(), reason)
+            }
+            Self::Encoder { code, stderr } => {
+                match code {
+                    Some(code) => write!(f, "ffmpeg exited with code {}", code)?,
+                    None => write!(f, "ffmpeg was killed by a signal")?,
+                }
+                for line in stderr {
+                    write!(f, "\n  {}", line)?;
+                }
+                Ok(())
+            }
+            Self::Loudness { stream, reason } => {
+                write!(
+                    f,
+                    "Failed to measure the loudness of stream {}: {}",
+                    stream, reason
+                )
+            }
+            Self::Cancelled => write!(f, "ffmpeg was cancelled"),
+            Self::Quality(reason) => write!(f, "Failed to measure the quality: {}", reason),
+            Self::Verification(issues) => {
+                write!(f, "Output verification failed: {}", issues.join(", "))
+            }
+            Self::InvalidPath(path) => write!(f, "Path is not valid UTF-8: {}", path.display()),
+            Self::Profile(reason) => write!(f, "Invalid profile: {}", reason),
+            Self::NotEnoughSpace {
+                path,
+                needed,
+                available,
+            } => write!(
+                f,
+                "Not enough free space in {}: {} bytes needed, {} available",
+                path.display(),
+                needed,
+                available
+            ),
+            Self::Notifier(e) => write!(f, "Discord notification failed: {}", e),
+            Self::Io { step, path, source } => {
+                write!(f, "Failed to {} {}: {}", step, path.display(), source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Notifier(e) => Some(e),
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Notifier(e)
+    }
+}
+
+/// Attaches the step that failed to an `io::Error`.
+pub(crate) trait IoResultExt<T> {
+    fn step(self, step: &'static str, path: &Path) -> Result<T>;
+}
+
+impl<T> IoResultExt<T> for io::Result<T> {
+    fn step(self, step: &'static str, path: &Path) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            step,
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
@@ -1,23 +1,34 @@
+use serde::Deserialize;
 use std::{
-    io::{self, Error, ErrorKind},
+    collections::{HashMap, VecDeque},
+    ffi::OsString,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
     path::{Path, PathBuf},
-    process::Stdio,
+    process::{Output, Stdio},
+    sync::Arc,
 };
 use tokio::{
     fs,
     io::{AsyncBufReadExt, BufReader},
-    process::Command,
-    sync::broadcast,
+    process::{ChildStderr, ChildStdout, Command},
+    sync::{Notify, broadcast},
 };
 
 use crate::{
-    config::FFMpegConfig,
-    ffprobe::{FFProbeResult, FFProbeResultStream},
+    codec::{VideoCodec, format_h264_level, h264_level, max_audio_channels},
+    config::{FFMpegConfig, ImageSubtitleMode, QualityMetric},
+    error::{Error, IoResultExt, Result},
+    ffprobe::{FFProbeResult, FFProbeResultStream, ffprobe},
+    profile::Profile,
+    utils::move_file,
+    validation::{ValidationIssueKind, ValidationReport},
 };
 
 #[derive(Clone)]
 pub struct FFMpegContext {
     pub probe: FFProbeResult,
+    pub report: ValidationReport,
     pub command: String,
     pub input_path: String,
     pub output_path: String,
@@ -27,85 +38,1677 @@ pub struct FFMpegContext {
 pub struct FFMpegProgress {
     pub speed: String,
     pub out_time_us: u64,
+    /// Current pass, starting at 1.
+    pub pass: u8,
+    pub passes: u8,
+}
+
+impl FFMpegProgress {
+    /// Time encoded over all passes, each of them going through `duration_us`.
+    pub fn total_out_time_us(&self, duration_us: u64) -> u64 {
+        (self.pass.saturating_sub(1) as u64) * duration_us + self.out_time_us
+    }
+}
+
+/// Why a transcode failed.
+#[derive(Clone)]
+pub struct FFMpegFailure {
+    pub message: String,
+    /// Last lines printed by ffmpeg, empty if it did not get to run.
+    pub stderr: Vec<String>,
+}
+
+impl From<&Error> for FFMpegFailure {
+    fn from(error: &Error) -> Self {
+        Self {
+            message: match error {
+                Error::Encoder {
+                    code: Some(code), ..
+                } => {
+                    format!("ffmpeg exited with code {}", code)
+                }
+                Error::Encoder { code: None, .. } => "ffmpeg was killed by a signal".into(),
+                error => error.to_string(),
+            },
+            stderr: error.stderr().to_vec(),
+        }
+    }
+}
+
+/// Score of the encoded video compared to the input.
+#[derive(Clone, Copy, Debug)]
+pub struct FFMpegQuality {
+    pub metric: QualityMetric,
+    /// Mean score over the samples.
+    pub score: f64,
+    /// Number of segments measured, 0 when the whole video was.
+    pub samples: u32,
+}
+
+impl fmt::Display for FFMpegQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.metric {
+            QualityMetric::Vmaf => write!(f, "VMAF {:.2}", self.score)?,
+            QualityMetric::Ssim => write!(f, "SSIM {:.4}", self.score)?,
+            QualityMetric::Psnr => write!(f, "PSNR {:.2} dB", self.score)?,
+        }
+        if self.samples > 0 {
+            write!(f, " ({} samples)", self.samples)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
 pub enum FFMpegEvent {
     START(FFMpegContext),
     PROGRESS(FFMpegContext, FFMpegProgress),
-    DONE(FFMpegContext),
-    ERROR(FFMpegContext),
+    DONE(FFMpegContext, Option<FFMpegQuality>),
+    /// Something went wrong without failing the transcode.
+    WARNING(FFMpegContext, String),
+    ERROR(FFMpegContext, FFMpegFailure),
+    CANCELLED(FFMpegContext),
     CLOSE(),
 }
 
-pub struct FFMpeg {
-    pub config: FFMpegConfig,
-    tx: broadcast::Sender<FFMpegEvent>,
-}
+/// What happens to an input stream when transcoding.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FFMpegStreamAction {
+    Copy,
+    Encode(String),
+    /// Re-encoded to the given codec and channel count next to the original.
+    Downmix(String, u8),
+    /// Burnt into the video.
+    Burn,
+    Extract(PathBuf),
+    Drop,
+}
+
+impl FFMpegStreamAction {
+    fn from_codec(codec: &str) -> Self {
+        match codec {
+            "copy" => Self::Copy,
+            codec => Self::Encode(codec.into()),
+        }
+    }
+}
+
+/// Subtitle stream written to its own file next to the output.
+#[derive(Clone)]
+pub struct FFMpegSidecar {
+    pub index: u8,
+    pub codec: &'static str,
+    pub format: &'static str,
+    pub path: PathBuf,
+}
+
+/// Values printed by loudnorm's analysis pass.
+#[derive(Deserialize, Clone, Debug)]
+pub struct FFMpegLoudnessMeasurement {
+    pub input_i: String,
+    pub input_tp: String,
+    pub input_lra: String,
+    pub input_thresh: String,
+    pub target_offset: String,
+}
+
+impl FFMpegLoudnessMeasurement {
+    /// Reads the measurement from the stderr of loudnorm's analysis pass.
+    pub fn parse(stderr: &str) -> std::result::Result<Self, String> {
+        // The measurement is the last object printed, ffmpeg's final stats
+        // may follow it
+        let no_measurement = || "loudnorm printed no measurement".to_string();
+        let json = stderr
+            .rfind('{')
+            .map(|start| &stderr[start..])
+            .ok_or_else(no_measurement)?;
+        serde_json::Deserializer::from_str(json)
+            .into_iter::<Self>()
+            .next()
+            .ok_or_else(no_measurement)?
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Measured loudness of the encoded audio outputs, keyed by input stream
+/// index and whether the output is the downmix.
+pub type FFMpegLoudness = HashMap<(u8, bool), FFMpegLoudnessMeasurement>;
+
+/// Outcome of the CRF search.
+#[derive(Clone, Debug)]
+pub struct FFMpegCrfSearch {
+    /// Highest CRF reaching the target, `None` when none did or the search
+    /// failed.
+    pub crf: Option<u8>,
+    /// Highest score measured.
+    pub best_score: f64,
+    /// Why the samples could not be measured.
+    pub error: Option<String>,
+}
+
+impl FFMpegCrfSearch {
+    /// Bisects `range` for the highest CRF whose score reaches `target`,
+    /// scores going down as the CRF goes up. Stops at the first error.
+    pub async fn bisect<F: Future<Output = Result<f64>>>(
+        range: (u8, u8),
+        target: f64,
+        mut score: impl FnMut(u8) -> F,
+    ) -> Result<Self> {
+        let (mut low, mut high) = range;
+        let mut search = Self {
+            crf: None,
+            best_score: f64::NEG_INFINITY,
+            error: None,
+        };
+        while low <= high {
+            let crf = low + (high - low) / 2;
+            let score = score(crf).await?;
+            search.best_score = search.best_score.max(score);
+            if score >= target {
+                search.crf = Some(crf);
+                low = crf + 1;
+            } else if crf == 0 {
+                break;
+            } else {
+                high = crf - 1;
+            }
+        }
+        Ok(search)
+    }
+}
+
+/// Handle used to stop a running transcode from another task.
+#[derive(Clone, Default)]
+pub struct FFMpegCancelToken(Arc<Notify>);
+
+impl FFMpegCancelToken {
+    pub fn cancel(&self) {
+        self.0.notify_one();
+    }
+}
+
+/// Converts PQ/HLG to BT.709 through linear light, requires zimg.
+const TONEMAP_FILTER: &str = "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv,format=yuv420p";
+
+/// Max true peak and loudness range used with loudnorm.
+const LOUDNORM_TRUE_PEAK: f64 = -1.0;
+const LOUDNORM_RANGE: f64 = 11.0;
+
+/// How far above the target bitrate or size an encode can land and still be
+/// accepted, rate control is not exact.
+const TARGET_TOLERANCE: f64 = 1.05;
+
+/// Samples encoded by the CRF search when quality sampling is off, the
+/// whole video being too long to encode at every step.
+const CRF_SEARCH_SAMPLES: u32 = 3;
+
+pub struct FFMpeg {
+    pub config: FFMpegConfig,
+    pub profile: Profile,
+    tx: broadcast::Sender<FFMpegEvent>,
+    cancel_token: FFMpegCancelToken,
+}
+
+impl FFMpeg {
+    pub fn new(config: &FFMpegConfig, profile: &Profile) -> Self {
+        let (tx, _) = broadcast::channel(16);
+        Self {
+            config: config.clone(),
+            profile: profile.clone(),
+            tx,
+            cancel_token: FFMpegCancelToken::default(),
+        }
+    }
+
+    fn emit(&self, event: FFMpegEvent) {
+        let _ = self.tx.send(event);
+    }
+
+    fn get_tmp_output_path(&self, output_path: &Path) -> PathBuf {
+        let mut file_name = output_path.file_name().unwrap_or_default().to_owned();
+        file_name.push(".part");
+        match &self.config.scratch_dir {
+            Some(scratch_dir) => {
+                // Outputs of different folders can share a name
+                let mut hasher = DefaultHasher::new();
+                output_path.hash(&mut hasher);
+                let mut scratch_name = OsString::from(format!("{:016x}-", hasher.finish()));
+                scratch_name.push(file_name);
+                Path::new(scratch_dir).join(scratch_name)
+            }
+            None => output_path.with_file_name(file_name),
+        }
+    }
+
+    /// Makes sure the temporary and final output folders can hold an
+    /// output as big as the input, or as the target size.
+    pub fn check_free_space(&self, probe: &FFProbeResult, output_path: &Path) -> Result<()> {
+        let needed = self.config.target_size.unwrap_or(
+            probe
+                .format
+                .size
+                .as_ref()
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(0),
+        );
+
+        let mut folders = vec![self.get_tmp_output_path(output_path)];
+        if self.config.scratch_dir.is_some() {
+            folders.push(output_path.to_path_buf());
+        }
+        for folder in folders.iter().filter_map(|path| path.parent()) {
+            let folder = if folder.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                folder
+            };
+            let available = fs4::available_space(folder).step("check free space in", folder)?;
+            if available < needed {
+                return Err(Error::NotEnoughSpace {
+                    path: folder.to_path_buf(),
+                    needed,
+                    available,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Path of a subtitle sidecar file, named `<output stem>.<lang>[.forced].<ext>`
+    /// like the files moved by `move_srt_files`.
+    pub fn get_sidecar_path(
+        output_path: &Path,
+        stream: &FFProbeResultStream,
+        extension: &str,
+        with_index: bool,
+    ) -> PathBuf {
+        let stem = output_path
+            .file_stem()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_default();
+        let mut name = format!("{}.{}", stem, stream.language());
+        if with_index {
+            name = format!("{}.{}", name, stream.index);
+        }
+        if stream.is_forced() {
+            name = format!("{}.forced", name);
+        }
+        output_path.with_file_name(format!("{}.{}", name, extension))
+    }
+
+    fn get_image_subtitle_format(stream: &FFProbeResultStream) -> (&'static str, &'static str) {
+        match stream.codec_name.as_deref() {
+            Some("hdmv_pgs_subtitle" | "pgssub") => ("sup", "sup"),
+            _ => ("mks", "matroska"),
+        }
+    }
+
+    /// Extra outputs written next to the main one.
+    pub fn get_sidecars(&self, probe: &FFProbeResult, output_path: &Path) -> Vec<FFMpegSidecar> {
+        let mut sidecars: Vec<FFMpegSidecar> = vec![];
+        for stream in self.get_selected_streams(probe) {
+            let (codec, extension, format) = if stream.is_image_subtitle() {
+                if self.config.image_subtitles != ImageSubtitleMode::Extract {
+                    continue;
+                }
+                let (extension, format) = Self::get_image_subtitle_format(stream);
+                ("copy", extension, format)
+            } else if stream.codec_type == "subtitle" && stream.codec_name.is_some() {
+                if !self.config.extract_text_subtitles {
+                    continue;
+                }
+                ("srt", "srt", "srt")
+            } else {
+                continue;
+            };
+            let mut path = Self::get_sidecar_path(output_path, stream, extension, false);
+            if sidecars.iter().any(|sidecar| sidecar.path == path) {
+                path = Self::get_sidecar_path(output_path, stream, extension, true);
+            }
+            sidecars.push(FFMpegSidecar {
+                index: stream.index,
+                codec,
+                format,
+                path,
+            });
+        }
+        sidecars
+    }
+
+    /// Forced bitmap subtitle to burn into the video, preferring the default
+    /// subtitle language.
+    fn get_burn_stream(&self, streams: &[&FFProbeResultStream]) -> Option<u8> {
+        if self.config.image_subtitles != ImageSubtitleMode::Burn {
+            return None;
+        }
+        let mut forced = streams
+            .iter()
+            .filter(|stream| stream.is_image_subtitle() && stream.is_forced());
+        let first = forced.clone().next()?;
+        let preferred = match &self.config.default_subtitle_language {
+            Some(language) => {
+                forced.find(|stream| stream.language().eq_ignore_ascii_case(language))
+            }
+            None => None,
+        };
+        Some(preferred.unwrap_or(first).index)
+    }
+
+    /// HDR sources are tonemapped unless the profile keeps them.
+    fn needs_tonemap(&self, stream: &FFProbeResultStream) -> bool {
+        !self.profile.hdr && stream.is_hdr()
+    }
+
+    /// Pixel format of the encoded video, 10 bits only to keep HDR.
+    fn get_pix_fmt(&self, stream: Option<&FFProbeResultStream>) -> &'static str {
+        match stream {
+            Some(stream) if self.profile.hdr && stream.is_hdr() => "yuv420p10le",
+            _ => "yuv420p",
+        }
+    }
+
+    /// Filter graph applied to a video stream, if it needs one.
+    fn get_video_filter(
+        &self,
+        stream: &FFProbeResultStream,
+        burn_stream: Option<u8>,
+    ) -> Option<String> {
+        let mut chains: Vec<String> = vec![];
+        let mut inputs = format!("[0:{}]", stream.index);
+        let mut filters: Vec<String> = vec![];
+
+        if self.needs_tonemap(stream) {
+            filters.push(TONEMAP_FILTER.into());
+        }
+
+        if let Some(burn_stream) = burn_stream {
+            // Subtitles are SDR, overlay them once the video is tonemapped
+            if !filters.is_empty() {
+                chains.push(format!(
+                    "{}{}[base{}]",
+                    inputs,
+                    filters.join(","),
+                    stream.index
+                ));
+                inputs = format!("[base{}]", stream.index);
+                filters.clear();
+            }
+            inputs = format!("{}[0:{}]", inputs, burn_stream);
+            filters.push("overlay".into());
+        }
+
+        if self.is_oversized(stream) {
+            filters.push(self.get_scale_filter());
+        }
+
+        if filters.is_empty() {
+            None
+        } else {
+            chains.push(format!(
+                "{}{}[v{}]",
+                inputs,
+                filters.join(","),
+                stream.index
+            ));
+            Some(chains.join(";"))
+        }
+    }
+
+    /// Downscales to the max width and height, keeping the aspect ratio.
+    fn get_scale_filter(&self) -> String {
+        let max = |max: Option<u32>, dimension: &str| match max {
+            Some(max) => format!("min({},{})", dimension, max),
+            None => dimension.into(),
+        };
+        format!(
+            "scale=w='{}':h='{}':force_original_aspect_ratio=decrease:force_divisible_by=2",
+            max(self.config.max_width, "iw"),
+            max(self.config.max_height, "ih")
+        )
+    }
+
+    /// Whether the stream is bigger than the max width or height.
+    fn is_oversized(&self, stream: &FFProbeResultStream) -> bool {
+        let exceeds = |size: Option<u32>, max: Option<u32>| match (size, max) {
+            (Some(size), Some(max)) => size > max,
+            _ => false,
+        };
+        exceeds(stream.width, self.config.max_width)
+            || exceeds(stream.height, self.config.max_height)
+    }
+
+    /// Size of the encoded video once scaled down to the max resolution.
+    fn get_output_size(&self, stream: &FFProbeResultStream) -> Option<(u32, u32)> {
+        let (width, height) = (stream.width?, stream.height?);
+        if !self.is_oversized(stream) {
+            return Some((width, height));
+        }
+        let ratio = [
+            self.config.max_width.map(|max| max as f64 / width as f64),
+            self.config.max_height.map(|max| max as f64 / height as f64),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(1.0, f64::min);
+        let scale = |size: u32| ((size as f64 * ratio) as u32 / 2 * 2).max(2);
+        Some((scale(width), scale(height)))
+    }
+
+    /// x264 profile of the encoded video.
+    fn get_h264_profile(&self, stream: &FFProbeResultStream) -> &'static str {
+        match self.get_pix_fmt(Some(stream)) {
+            "yuv420p10le" => "high10",
+            _ => "high",
+        }
+    }
+
+    /// Lowest H.264 level for the encoded video, assuming 30 fps when the
+    /// frame rate is unknown.
+    fn get_h264_level(&self, stream: &FFProbeResultStream, max_bitrate: u32) -> u8 {
+        let (width, height) = self.get_output_size(stream).unwrap_or((1920, 1080));
+        h264_level(
+            width,
+            height,
+            stream.frame_rate().unwrap_or(30.0),
+            max_bitrate,
+            self.get_h264_profile(stream),
+        )
+    }
+
+    /// Issues of an H.264 stream that would be copied with a profile or a
+    /// level players targeted by the profile may not decode.
+    fn get_h264_issues(&self, stream: &FFProbeResultStream) -> Vec<ValidationIssueKind> {
+        let mut issues = vec![];
+
+        let target_profile = self.get_h264_profile(stream);
+        if let Some(profile) = &stream.profile {
+            let mut supported = vec!["Constrained Baseline", "Baseline", "Main", "High"];
+            if target_profile == "high10" {
+                supported.push("High 10");
+            }
+            if !supported.contains(&profile.as_str()) {
+                issues.push(ValidationIssueKind::ProfileMismatch {
+                    profile: profile.clone(),
+                    expected: target_profile.into(),
+                });
+            }
+        }
+
+        let max_bitrate = self
+            .config
+            .target_bitrate
+            .unwrap_or(self.config.video_maxrate);
+        // Levels the encode would use anyway are kept
+        let max_level = self
+            .config
+            .h264_max_level
+            .max(self.get_h264_level(stream, max_bitrate));
+        if let Some(level) = stream.level
+            && level > max_level as i32
+        {
+            issues.push(ValidationIssueKind::LevelTooHigh {
+                level: format_h264_level(level.clamp(0, u8::MAX as i32) as u8),
+                max: format_h264_level(max_level),
+            });
+        }
+
+        issues
+    }
+
+    fn video_codec(&self) -> Option<VideoCodec> {
+        VideoCodec::parse(&self.profile.video_codec)
+    }
+
+    fn is_target_video_codec(&self, codec_name: &str) -> bool {
+        match (self.video_codec(), VideoCodec::parse(codec_name)) {
+            (Some(target), Some(codec)) => target == codec,
+            _ => codec_name.eq_ignore_ascii_case(&self.profile.video_codec),
+        }
+    }
+
+    /// CRF used when not searching or when no CRF reaches the target.
+    pub fn get_crf_level(&self) -> u8 {
+        match self.video_codec() {
+            Some(codec) => self
+                .config
+                .crf_level
+                .unwrap_or(codec.default_crf())
+                .min(codec.max_crf()),
+            None => self.config.crf_level.unwrap_or(23),
+        }
+    }
+
+    /// Bitrate of the audio tracks written to the output.
+    fn get_audio_bitrate(&self, probe: &FFProbeResult) -> u64 {
+        let audio_bitrate = self.config.audio_bitrate as u64;
+        let surround_bitrate = self.config.surround_bitrate as u64;
+        self.get_selected_streams(probe)
+            .iter()
+            .filter(|stream| stream.codec_type == "audio")
+            .map(|stream| {
+                let bit_rate = stream.bit_rate.as_ref().and_then(|b| b.parse::<u64>().ok());
+                if self.is_surround(stream) {
+                    let surround = match self.get_surround_target(stream) {
+                        "copy" => bit_rate.unwrap_or(surround_bitrate),
+                        _ => surround_bitrate,
+                    };
+                    surround + audio_bitrate
+                } else {
+                    match self.get_audio_target(stream) {
+                        "copy" => bit_rate.unwrap_or(audio_bitrate),
+                        _ => audio_bitrate,
+                    }
+                }
+            })
+            .sum()
+    }
+
+    /// Video bitrate to encode at, `None` when using a CRF.
+    pub fn get_target_video_bitrate(&self, probe: &FFProbeResult) -> Option<u32> {
+        if let Some(target_bitrate) = self.config.target_bitrate {
+            return Some(target_bitrate);
+        }
+        let target_size = self.config.target_size?;
+        let duration = probe.format.duration.parse::<f64>().ok()?;
+        if duration <= 0.0 {
+            return None;
+        }
+        // Leave 2% of the size to the container overhead
+        let total_bitrate = (target_size as f64 * 8.0 * 0.98 / duration) as u64;
+        let video_bitrate = total_bitrate.saturating_sub(self.get_audio_bitrate(probe));
+        // Anything lower can't produce a watchable video
+        Some(video_bitrate.clamp(100_000, u32::MAX as u64) as u32)
+    }
+
+    /// Whether the video is encoded at a target bitrate with two libx264 passes.
+    pub fn is_two_pass(&self, probe: &FFProbeResult) -> bool {
+        let selected_streams = self.get_selected_streams(probe);
+        let burn_stream = self.get_burn_stream(&selected_streams);
+        self.get_target_video_bitrate(probe).is_some()
+            && self.video_codec() == Some(VideoCodec::H264)
+            && selected_streams
+                .iter()
+                .filter(|stream| stream.codec_type == "video")
+                .enumerate()
+                .any(|(i, stream)| {
+                    let filter =
+                        self.get_video_filter(stream, if i == 0 { burn_stream } else { None });
+                    self.get_video_target(probe, stream, filter.is_some()) != "copy"
+                })
+    }
+
+    fn get_passlog_path(&self, output_path: &Path) -> PathBuf {
+        self.get_tmp_output_path(output_path)
+            .with_extension("passlog")
+    }
+
+    async fn remove_passlog_files(&self, output_path: &Path) {
+        let passlog_path = self.get_passlog_path(output_path);
+        for suffix in ["-0.log", "-0.log.mbtree"] {
+            let _ = fs::remove_file(format!("{}{}", passlog_path.display(), suffix)).await;
+        }
+    }
+
+    /// Reasons why a stream can't be copied as is to the output.
+    pub fn get_stream_issues(&self, stream: &FFProbeResultStream) -> Vec<ValidationIssueKind> {
+        let mut issues = vec![];
+        let Some(codec_name) = &stream.codec_name else {
+            return issues;
+        };
+
+        let codec_mismatch = |expected: &str| ValidationIssueKind::CodecMismatch {
+            codec_type: stream.codec_type.clone(),
+            codec: codec_name.clone(),
+            expected: expected.into(),
+        };
+
+        match stream.codec_type.as_str() {
+            "video" => {
+                if !self.is_target_video_codec(codec_name) {
+                    issues.push(codec_mismatch(&self.profile.video_codec));
+                }
+                if self.needs_tonemap(stream) {
+                    issues.push(ValidationIssueKind::Hdr {
+                        color_transfer: stream.color_transfer.clone().unwrap_or_default(),
+                    });
+                } else if let Some(pix_fmt) = &stream.pix_fmt
+                    && pix_fmt != self.get_pix_fmt(Some(stream))
+                {
+                    issues.push(ValidationIssueKind::PixelFormatMismatch {
+                        pix_fmt: pix_fmt.clone(),
+                        expected: self.get_pix_fmt(Some(stream)).into(),
+                    });
+                }
+                if self.is_oversized(stream) {
+                    issues.push(ValidationIssueKind::ResolutionTooHigh {
+                        width: stream.width.unwrap_or(0),
+                        height: stream.height.unwrap_or(0),
+                        max_width: self.config.max_width,
+                        max_height: self.config.max_height,
+                    });
+                }
+                if self.video_codec() == Some(VideoCodec::H264)
+                    && VideoCodec::parse(codec_name) == Some(VideoCodec::H264)
+                {
+                    issues.extend(self.get_h264_issues(stream));
+                }
+            }
+            "audio" => {
+                let channels = stream.channels.unwrap_or(2);
+                if channels <= self.profile.audio_channels {
+                    if !codec_name.eq_ignore_ascii_case(&self.profile.audio_codec) {
+                        issues.push(codec_mismatch(&self.profile.audio_codec));
+                    }
+                } else {
+                    match &self.profile.surround_codec {
+                        Some(surround_codec) if surround_codec != "copy" => {
+                            if !codec_name.eq_ignore_ascii_case(surround_codec) {
+                                issues.push(codec_mismatch(surround_codec));
+                            }
+                            if let Some(max) = max_audio_channels(surround_codec)
+                                && channels > max
+                            {
+                                issues.push(ValidationIssueKind::TooManyChannels { channels, max });
+                            }
+                        }
+                        Some(_) => {}
+                        None => issues.push(ValidationIssueKind::TooManyChannels {
+                            channels,
+                            max: self.profile.audio_channels,
+                        }),
+                    }
+                }
+            }
+            "subtitle" => match &self.profile.subtitle_codec {
+                Some(_) if stream.is_image_subtitle() => {
+                    issues.push(ValidationIssueKind::UnsupportedSubtitle {
+                        codec: codec_name.clone(),
+                    });
+                }
+                Some(subtitle_codec) => {
+                    if !codec_name.eq_ignore_ascii_case(subtitle_codec) {
+                        issues.push(codec_mismatch(subtitle_codec));
+                    }
+                }
+                None => issues.push(ValidationIssueKind::UnsupportedSubtitle {
+                    codec: codec_name.clone(),
+                }),
+            },
+            _ => {}
+        }
+
+        issues
+    }
+
+    /// Bitrate issue of a video stream, checked against the target bitrate
+    /// when there is one and the max rate otherwise.
+    fn get_bitrate_issue(
+        &self,
+        probe: &FFProbeResult,
+        stream: &FFProbeResultStream,
+    ) -> Option<ValidationIssueKind> {
+        let bit_rate = stream.bit_rate.as_ref()?.parse::<u32>().unwrap_or(0);
+        let max = match self.get_target_video_bitrate(probe) {
+            Some(target) => (target as f64 * TARGET_TOLERANCE) as u32,
+            None => self.config.video_maxrate,
+        };
+        (bit_rate > max).then_some(ValidationIssueKind::BitrateTooHigh { bit_rate, max })
+    }
+
+    /// Whether the file is bigger than the target size.
+    fn get_size_issue(&self, probe: &FFProbeResult) -> Option<ValidationIssueKind> {
+        let target_size = self.config.target_size?;
+        let size = probe.format.size.as_ref()?.parse::<u64>().ok()?;
+        let max = (target_size as f64 * TARGET_TOLERANCE) as u64;
+        (size > max).then_some(ValidationIssueKind::SizeTooLarge { size, max })
+    }
+
+    pub fn is_stream_valid(&self, stream: &FFProbeResultStream) -> bool {
+        self.get_stream_issues(stream).is_empty()
+    }
+
+    /// Lists the reasons why the file needs to be transcoded.
+    pub fn validate(&self, probe: &FFProbeResult) -> ValidationReport {
+        let mut report = ValidationReport::default();
+
+        let has_video = probe
+            .streams
+            .iter()
+            .any(|stream| stream.codec_type == "video");
+        for stream in probe.streams.iter() {
+            for issue in self.get_stream_issues(stream) {
+                report.push(Some(stream.index), issue);
+            }
+            if stream.codec_type == "video"
+                && let Some(issue) = self.get_bitrate_issue(probe, stream)
+            {
+                report.push(Some(stream.index), issue);
+            }
+        }
+        // Only the video gets shrunk to the target size
+        if has_video && let Some(issue) = self.get_size_issue(probe) {
+            report.push(None, issue);
+        }
+
+        let selected_streams = self.get_selected_streams(probe);
+        for stream in probe.streams.iter() {
+            if !selected_streams
+                .iter()
+                .any(|selected| selected.index == stream.index)
+            {
+                report.push(
+                    Some(stream.index),
+                    ValidationIssueKind::FilteredOut {
+                        codec_type: stream.codec_type.clone(),
+                    },
+                );
+            }
+        }
+
+        for (codec_type, language) in [
+            ("audio", &self.config.default_audio_language),
+            ("subtitle", &self.config.default_subtitle_language),
+        ] {
+            if let Some(default_index) =
+                self.get_default_stream(&selected_streams, codec_type, language)
+                && selected_streams
+                    .iter()
+                    .filter(|stream| stream.codec_type == codec_type && self.is_muxed(stream))
+                    .any(|stream| stream.is_default() != (stream.index == default_index))
+            {
+                report.push(
+                    Some(default_index),
+                    ValidationIssueKind::NotDefault {
+                        codec_type: codec_type.into(),
+                    },
+                );
+            }
+        }
+
+        // Surround tracks are only kept next to a downmixed one
+        let is_audio = |stream: &&FFProbeResultStream| stream.codec_type == "audio";
+        let has_surround = probe
+            .streams
+            .iter()
+            .filter(is_audio)
+            .any(|stream| stream.channels.unwrap_or(2) > self.profile.audio_channels);
+        let has_downmix = probe
+            .streams
+            .iter()
+            .filter(is_audio)
+            .any(|stream| stream.channels.unwrap_or(2) <= self.profile.audio_channels);
+        if self.profile.surround_codec.is_some() && has_surround && !has_downmix {
+            report.push(None, ValidationIssueKind::MissingDownmix);
+        }
+
+        report
+    }
+
+    pub fn is_valid(&self, probe: &FFProbeResult) -> bool {
+        self.validate(probe).is_valid()
+    }
+
+    fn is_stream_kept(
+        stream: &FFProbeResultStream,
+        languages: &[String],
+        drop_commentary: bool,
+    ) -> bool {
+        if drop_commentary && stream.is_commentary() {
+            return false;
+        }
+        languages.is_empty()
+            || languages
+                .iter()
+                .any(|language| language.eq_ignore_ascii_case(stream.language()))
+    }
+
+    /// Streams that should end up in the output once the language filters
+    /// are applied. Audio is never dropped entirely: when no track matches,
+    /// all of them are kept.
+    pub fn get_selected_streams<'a>(
+        &self,
+        probe: &'a FFProbeResult,
+    ) -> Vec<&'a FFProbeResultStream> {
+        let drop_commentary = self.config.drop_commentary;
+        let keep_all_audio = !probe.streams.iter().any(|stream| {
+            stream.codec_type == "audio"
+                && Self::is_stream_kept(stream, &self.config.audio_languages, drop_commentary)
+        });
+
+        probe
+            .streams
+            .iter()
+            .filter(|stream| match stream.codec_type.as_str() {
+                "audio" => {
+                    keep_all_audio
+                        || Self::is_stream_kept(
+                            stream,
+                            &self.config.audio_languages,
+                            drop_commentary,
+                        )
+                }
+                "subtitle" => {
+                    Self::is_stream_kept(stream, &self.config.subtitle_languages, drop_commentary)
+                }
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// Whether a selected stream ends up in the output, bitmap subtitles
+    /// being dropped, extracted or burnt.
+    fn is_muxed(&self, stream: &FFProbeResultStream) -> bool {
+        stream.codec_type != "subtitle" || self.get_subtitle_target(stream).is_some()
+    }
+
+    /// Index of the muxed stream of the given type that should be flagged as
+    /// default, full subtitles are preferred over forced ones.
+    fn get_default_stream(
+        &self,
+        streams: &[&FFProbeResultStream],
+        codec_type: &str,
+        language: &Option<String>,
+    ) -> Option<u8> {
+        let language = language.as_ref()?;
+        let mut candidates = streams.iter().filter(|stream| {
+            stream.codec_type == codec_type
+                && self.is_muxed(stream)
+                && stream.language().eq_ignore_ascii_case(language)
+        });
+        let first = candidates.clone().next()?;
+        Some(
+            candidates
+                .find(|stream| !stream.is_forced())
+                .unwrap_or(first)
+                .index,
+        )
+    }
+
+    fn get_disposition(stream: &FFProbeResultStream, is_default: bool) -> String {
+        let mut flags = vec![];
+        if is_default {
+            flags.push("default");
+        }
+        if stream.is_forced() {
+            flags.push("forced");
+        }
+        if flags.is_empty() {
+            "0".into()
+        } else {
+            flags.join("+")
+        }
+    }
+
+    fn get_channels_title(channels: u8) -> String {
+        match channels {
+            1 => "Mono".into(),
+            2 => "Stereo".into(),
+            6 => "5.1".into(),
+            8 => "7.1".into(),
+            _ => format!("{} channels", channels),
+        }
+    }
+
+    fn get_video_target(
+        &self,
+        probe: &FFProbeResult,
+        stream: &FFProbeResultStream,
+        has_filter: bool,
+    ) -> &str {
+        if !has_filter
+            && self.is_stream_valid(stream)
+            && self.get_bitrate_issue(probe, stream).is_none()
+            && self.get_size_issue(probe).is_none()
+        {
+            return "copy";
+        }
+        match self.video_codec() {
+            Some(codec) => codec.encoder(),
+            None => self.profile.video_codec.as_str(),
+        }
+    }
+
+    fn is_surround(&self, stream: &FFProbeResultStream) -> bool {
+        self.profile.surround_codec.is_some()
+            && stream.channels.unwrap_or(2) > self.profile.audio_channels
+    }
+
+    fn get_surround_target(&self, stream: &FFProbeResultStream) -> &str {
+        match &self.profile.surround_codec {
+            Some(surround_codec) if !self.is_stream_valid(stream) => surround_codec.as_str(),
+            _ => "copy",
+        }
+    }
+
+    fn get_audio_target(&self, stream: &FFProbeResultStream) -> &str {
+        if self.is_stream_valid(stream) {
+            "copy"
+        } else {
+            self.profile.audio_codec.as_str()
+        }
+    }
+
+    /// Codec for a subtitle muxed in the output, `None` when it can't be.
+    fn get_subtitle_target(&self, stream: &FFProbeResultStream) -> Option<&str> {
+        if stream.codec_name.is_none() || stream.is_image_subtitle() {
+            return None;
+        }
+        let subtitle_codec = self.profile.subtitle_codec.as_deref()?;
+        if self.is_stream_valid(stream) {
+            Some("copy")
+        } else {
+            Some(subtitle_codec)
+        }
+    }
+
+    /// Audio outputs that get encoded, with whether they are a downmix.
+    fn get_encoded_audio<'a>(
+        &self,
+        probe: &'a FFProbeResult,
+    ) -> Vec<(&'a FFProbeResultStream, bool)> {
+        let mut outputs = vec![];
+        for stream in self.get_selected_streams(probe) {
+            if stream.codec_type != "audio" {
+                continue;
+            }
+            if self.is_surround(stream) {
+                if self.get_surround_target(stream) != "copy" {
+                    outputs.push((stream, false));
+                }
+                outputs.push((stream, true));
+            } else if self.get_audio_target(stream) != "copy" {
+                let downmix = stream.channels.unwrap_or(2) > self.profile.audio_channels;
+                outputs.push((stream, downmix));
+            }
+        }
+        outputs
+    }
+
+    /// Filters run before loudnorm, both when measuring and encoding.
+    fn get_audio_prefilters(&self, stream: &FFProbeResultStream, downmix: bool) -> Vec<String> {
+        let mut filters = vec![];
+        if !downmix {
+            return filters;
+        }
+        if self.config.dialog_boost
+            && stream.channels == Some(6)
+            && self.profile.audio_channels == 2
+        {
+            let (left, right) = match &stream.channel_layout {
+                Some(layout) if layout.contains("side") => ("SL", "SR"),
+                _ => ("BL", "BR"),
+            };
+            filters.push(format!(
+                "pan=stereo|FL<FC+0.30*FL+0.30*{}|FR<FC+0.30*FR+0.30*{}",
+                left, right
+            ));
+        } else if self.config.loudnorm {
+            // Measure the downmix rather than the source channels
+            filters.push(format!(
+                "aformat=channel_layouts={}c",
+                self.profile.audio_channels
+            ));
+        }
+        filters
+    }
+
+    fn get_loudnorm_filter(&self, measurement: Option<&FFMpegLoudnessMeasurement>) -> String {
+        let mut filter = format!(
+            "loudnorm=I={}:TP={}:LRA={}",
+            self.config.loudnorm_target, LOUDNORM_TRUE_PEAK, LOUDNORM_RANGE
+        );
+        if let Some(measurement) = measurement {
+            filter = format!(
+                "{}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+                filter,
+                measurement.input_i,
+                measurement.input_tp,
+                measurement.input_lra,
+                measurement.input_thresh,
+                measurement.target_offset
+            );
+        }
+        filter
+    }
+
+    /// Filter chain of an encoded audio output, without measurement
+    /// loudnorm falls back to its single pass dynamic mode.
+    fn get_audio_filter(
+        &self,
+        stream: &FFProbeResultStream,
+        downmix: bool,
+        loudness: &FFMpegLoudness,
+    ) -> Option<String> {
+        let mut filters = self.get_audio_prefilters(stream, downmix);
+        if self.config.loudnorm {
+            filters.push(self.get_loudnorm_filter(loudness.get(&(stream.index, downmix))));
+            // loudnorm works at 192kHz
+            filters.push(format!(
+                "aresample={}",
+                stream.sample_rate.as_deref().unwrap_or("48000")
+            ));
+        }
+        if filters.is_empty() {
+            None
+        } else {
+            Some(filters.join(","))
+        }
+    }
+
+    /// Error for an analysis run of ffmpeg that failed, with the end of what
+    /// it printed.
+    fn get_encoder_error(&self, output: &Output) -> Error {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let lines = stderr.lines().collect::<Vec<_>>();
+        let first_kept = lines.len().saturating_sub(self.config.stderr_lines);
+        Error::Encoder {
+            code: output.status.code(),
+            stderr: lines[first_kept..].iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// Runs loudnorm's analysis on every audio output that gets encoded.
+    pub async fn measure_loudness(&self, probe: &FFProbeResult) -> Result<FFMpegLoudness> {
+        let mut loudness = FFMpegLoudness::new();
+        if !self.config.loudnorm {
+            return Ok(loudness);
+        }
+
+        for (stream, downmix) in self.get_encoded_audio(probe) {
+            let mut filters = self.get_audio_prefilters(stream, downmix);
+            filters.push(format!(
+                "{}:print_format=json",
+                self.get_loudnorm_filter(None)
+            ));
+            let output = Command::new("ffmpeg")
+                .arg("-hide_banner")
+                .arg("-nostats")
+                .arg("-i")
+                .arg(probe.format.filename.as_str())
+                .arg("-map")
+                .arg(format!("0:{}", stream.index))
+                .arg("-af")
+                .arg(filters.join(","))
+                .arg("-f")
+                .arg("null")
+                .arg("-")
+                .kill_on_drop(true)
+                .output()
+                .await
+                .step("run ffmpeg on", Path::new(&probe.format.filename))?;
+
+            if !output.status.success() {
+                return Err(self.get_encoder_error(&output));
+            }
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let measurement =
+                FFMpegLoudnessMeasurement::parse(&stderr).map_err(|reason| Error::Loudness {
+                    stream: stream.index,
+                    reason,
+                })?;
+            loudness.insert((stream.index, downmix), measurement);
+        }
+
+        Ok(loudness)
+    }
+
+    /// Video stream the quality is measured on, none when it is copied.
+    fn get_quality_stream<'a>(
+        &self,
+        probe: &'a FFProbeResult,
+        output_path: &Path,
+    ) -> Option<&'a FFProbeResultStream> {
+        self.get_stream_actions(probe, output_path)
+            .into_iter()
+            .filter_map(|(index, action)| match action {
+                FFMpegStreamAction::Encode(_) => {
+                    probe.streams.iter().find(|stream| stream.index == index)
+                }
+                _ => None,
+            })
+            .find(|stream| stream.codec_type == "video")
+    }
+
+    /// Start and length in seconds of the segments to measure, a single
+    /// `None` for the whole video when `samples` is 0 or it is too short.
+    pub fn get_quality_segments(
+        &self,
+        probe: &FFProbeResult,
+        samples: u32,
+    ) -> Vec<Option<(f64, f64)>> {
+        let length = self.config.quality_sample_seconds as f64;
+        let duration = probe.format.duration_seconds().unwrap_or(0.0);
+        if samples == 0 || duration <= samples as f64 * length {
+            return vec![None];
+        }
+        // Centered in as many equal parts of the video
+        (0..samples)
+            .map(|i| {
+                let center = duration * (2 * i + 1) as f64 / (2 * samples) as f64;
+                Some(((center - length / 2.0).max(0.0), length))
+            })
+            .collect()
+    }
+
+    /// Filter graph comparing the encoded video, first input, to the source
+    /// stream, second input, brought to the same size and pixel format.
+    fn get_quality_filter(&self, metric: QualityMetric, stream: &FFProbeResultStream) -> String {
+        let pix_fmt = self.get_pix_fmt(Some(stream));
+        let mut reference_filters: Vec<String> = vec![];
+        if self.needs_tonemap(stream) {
+            reference_filters.push(TONEMAP_FILTER.into());
+        }
+        // Scaled like the encode was, to the exact same size
+        if self.is_oversized(stream) {
+            reference_filters.push(self.get_scale_filter());
+        }
+        reference_filters.push(format!("format={},setpts=PTS-STARTPTS", pix_fmt));
+        format!(
+            "[0:v:0]format={},setpts=PTS-STARTPTS[distorted];[1:{}]{}[reference];[distorted][reference]{}",
+            pix_fmt,
+            stream.index,
+            reference_filters.join(","),
+            match metric {
+                QualityMetric::Vmaf => "libvmaf",
+                QualityMetric::Ssim => "ssim",
+                QualityMetric::Psnr => "psnr",
+            }
+        )
+    }
+
+    /// Score printed last by the quality filter.
+    fn parse_quality_score(metric: QualityMetric, stderr: &str) -> Option<f64> {
+        let marker = match metric {
+            QualityMetric::Vmaf => "VMAF score: ",
+            QualityMetric::Ssim => " All:",
+            QualityMetric::Psnr => " average:",
+        };
+        let start = stderr.rfind(marker)? + marker.len();
+        stderr[start..].split_whitespace().next()?.parse().ok()
+    }
+
+    /// `is_sample` tells whether the encoded file only holds the segment.
+    pub fn get_quality_command(
+        &self,
+        metric: QualityMetric,
+        stream: &FFProbeResultStream,
+        input_path: &Path,
+        encoded_path: &Path,
+        segment: Option<(f64, f64)>,
+        is_sample: bool,
+    ) -> Command {
+        let mut cmd = Command::new("ffmpeg");
+        cmd.arg("-hide_banner").arg("-nostats");
+        for (path, seek) in [(encoded_path, !is_sample), (input_path, true)] {
+            if seek && let Some((start, length)) = segment {
+                cmd.arg("-ss")
+                    .arg(format!("{:.3}", start))
+                    .arg("-t")
+                    .arg(format!("{:.3}", length));
+            }
+            cmd.arg("-i").arg(path);
+        }
+        cmd.arg("-lavfi")
+            .arg(self.get_quality_filter(metric, stream))
+            .arg("-f")
+            .arg("null")
+            .arg("-");
+        cmd
+    }
+
+    async fn run_quality_command(
+        &self,
+        metric: QualityMetric,
+        mut cmd: Command,
+        encoded_path: &Path,
+    ) -> Result<f64> {
+        let output = cmd
+            .kill_on_drop(true)
+            .output()
+            .await
+            .step("run ffmpeg on", encoded_path)?;
+        if !output.status.success() {
+            return Err(self.get_encoder_error(&output));
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Self::parse_quality_score(metric, &stderr)
+            .ok_or_else(|| Error::Quality(format!("{} printed no score", metric.name())))
+    }
+
+    /// Lowest and highest CRF tried by the search.
+    pub fn get_crf_search_range(&self) -> (u8, u8) {
+        self.video_codec()
+            .map_or((16, 32), |codec| codec.crf_search_range())
+    }
+
+    /// Segments encoded by the CRF search.
+    pub fn get_crf_search_segments(&self, probe: &FFProbeResult) -> Vec<Option<(f64, f64)>> {
+        let samples = match self.config.quality_samples {
+            0 => CRF_SEARCH_SAMPLES,
+            samples => samples,
+        };
+        self.get_quality_segments(probe, samples)
+    }
+
+    /// Encodes a segment of the video stream alone, as the full encode would
+    /// at the given CRF.
+    fn get_sample_command(
+        &self,
+        probe: &FFProbeResult,
+        stream: &FFProbeResultStream,
+        segment: Option<(f64, f64)>,
+        crf: u8,
+        sample_path: &Path,
+    ) -> Command {
+        let maxrate = self.config.video_maxrate;
+        let mut cmd = Command::new("ffmpeg");
+        if let Some((start, length)) = segment {
+            cmd.arg("-ss")
+                .arg(format!("{:.3}", start))
+                .arg("-t")
+                .arg(format!("{:.3}", length));
+        }
+        cmd.arg("-i")
+            .arg(probe.format.filename.as_str())
+            .arg("-y")
+            .arg("-hide_banner")
+            .arg("-loglevel")
+            .arg("error")
+            .arg("-f")
+            .arg(&self.profile.format);
+
+        let filter = self.get_video_filter(stream, None);
+        let codec = self
+            .get_video_target(probe, stream, filter.is_some())
+            .to_string();
+        match filter {
+            Some(filter) => {
+                cmd.arg("-filter_complex")
+                    .arg(filter)
+                    .arg("-map")
+                    .arg(format!("[v{}]", stream.index));
+            }
+            None => {
+                cmd.arg("-map").arg(format!("0:{}", stream.index));
+            }
+        }
+        cmd.arg("-c:v").arg(codec).arg("-crf").arg(crf.to_string());
+        if let Some(preset) = &self.config.preset {
+            cmd.arg("-preset").arg(match self.video_codec() {
+                Some(codec) => codec.preset(preset),
+                None => preset.clone(),
+            });
+        }
+        cmd.arg("-pix_fmt")
+            .arg(self.get_pix_fmt(Some(stream)))
+            .arg("-maxrate")
+            .arg(maxrate.to_string())
+            .arg("-bufsize")
+            .arg((maxrate * 2).to_string())
+            .arg(sample_path);
+        cmd
+    }
+
+    /// Mean score of the segments encoded at the given CRF.
+    async fn measure_crf(
+        &self,
+        probe: &FFProbeResult,
+        stream: &FFProbeResultStream,
+        segments: &[Option<(f64, f64)>],
+        crf: u8,
+        output_path: &Path,
+    ) -> Result<f64> {
+        let metric = self.config.quality_metric.unwrap_or(QualityMetric::Vmaf);
+        let name = output_path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| Error::InvalidPath(output_path.to_path_buf()))?;
+        let mut total = 0.0;
+        for (i, segment) in segments.iter().enumerate() {
+            let sample_path = self
+                .get_tmp_output_path(&output_path.with_file_name(format!("{}.sample{}", name, i)));
+
+            let output = self
+                .get_sample_command(probe, stream, *segment, crf, &sample_path)
+                .kill_on_drop(true)
+                .output()
+                .await
+                .step("run ffmpeg on", Path::new(&probe.format.filename))?;
+            if !output.status.success() {
+                let _ = fs::remove_file(&sample_path).await;
+                return Err(self.get_encoder_error(&output));
+            }
+
+            let cmd = self.get_quality_command(
+                metric,
+                stream,
+                Path::new(&probe.format.filename),
+                &sample_path,
+                *segment,
+                true,
+            );
+            let score = self.run_quality_command(metric, cmd, &sample_path).await;
+            let _ = fs::remove_file(&sample_path).await;
+            total += score?;
+        }
+        Ok(total / segments.len() as f64)
+    }
+
+    /// Looks for the highest CRF whose samples reach the target quality.
+    /// `None` when not searching or the video is not encoded. Failing to
+    /// measure the samples is reported in the result, as the configured CRF
+    /// can still be used.
+    pub async fn search_crf(
+        &self,
+        probe: &FFProbeResult,
+        output_path: &Path,
+    ) -> Result<Option<FFMpegCrfSearch>> {
+        let Some(target) = self.config.target_quality else {
+            return Ok(None);
+        };
+        if self.get_target_video_bitrate(probe).is_some() {
+            return Ok(None);
+        }
+        let Some(stream) = self.get_quality_stream(probe, output_path) else {
+            return Ok(None);
+        };
 
-impl FFMpeg {
-    pub fn new(config: &FFMpegConfig) -> Self {
-        let (tx, _) = broadcast::channel(1);
-        Self {
-            config: config.clone(),
-            tx,
+        let segments = self.get_crf_search_segments(probe);
+        let search = FFMpegCrfSearch::bisect(self.get_crf_search_range(), target, |crf| {
+            self.measure_crf(probe, stream, &segments, crf, output_path)
+        })
+        .await;
+        match search {
+            Ok(search) => Ok(Some(search)),
+            Err(Error::Cancelled) => Err(Error::Cancelled),
+            Err(error) => Ok(Some(FFMpegCrfSearch {
+                crf: None,
+                best_score: f64::NEG_INFINITY,
+                error: Some(error.to_string()),
+            })),
         }
     }
 
-    fn emit(&mut self, event: FFMpegEvent) {
-        let _ = self.tx.send(event);
+    /// Compares the encoded video to the input with the configured metric,
+    /// on sampled segments if asked to.
+    pub async fn measure_quality(
+        &self,
+        probe: &FFProbeResult,
+        encoded_path: &Path,
+        output_path: &Path,
+    ) -> Result<Option<FFMpegQuality>> {
+        let Some(metric) = self.config.quality_metric else {
+            return Ok(None);
+        };
+        let Some(stream) = self.get_quality_stream(probe, output_path) else {
+            return Ok(None);
+        };
+
+        let segments = self.get_quality_segments(probe, self.config.quality_samples);
+        let mut total = 0.0;
+        for segment in segments.iter() {
+            let cmd = self.get_quality_command(
+                metric,
+                stream,
+                Path::new(&probe.format.filename),
+                encoded_path,
+                *segment,
+                false,
+            );
+            total += self.run_quality_command(metric, cmd, encoded_path).await?;
+        }
+
+        Ok(Some(FFMpegQuality {
+            metric,
+            score: total / segments.len() as f64,
+            samples: segments.iter().flatten().count() as u32,
+        }))
+    }
+
+    /// Actions of the streams written to the output, in output order.
+    fn get_output_stream_actions(
+        &self,
+        probe: &FFProbeResult,
+        output_path: &Path,
+    ) -> Vec<FFMpegStreamAction> {
+        self.get_stream_actions(probe, output_path)
+            .into_iter()
+            .map(|(_, action)| action)
+            .filter(|action| {
+                !matches!(
+                    action,
+                    FFMpegStreamAction::Burn
+                        | FFMpegStreamAction::Extract(_)
+                        | FFMpegStreamAction::Drop
+                )
+            })
+            .collect()
+    }
+
+    /// Number of video, audio and subtitle streams the output should have.
+    fn get_expected_stream_counts(
+        &self,
+        probe: &FFProbeResult,
+        output_path: &Path,
+    ) -> [(&'static str, usize); 3] {
+        let mut counts = [("video", 0), ("audio", 0), ("subtitle", 0)];
+        for (index, action) in self.get_stream_actions(probe, output_path) {
+            if matches!(
+                action,
+                FFMpegStreamAction::Burn
+                    | FFMpegStreamAction::Extract(_)
+                    | FFMpegStreamAction::Drop
+            ) {
+                continue;
+            }
+            if let Some(stream) = probe.streams.iter().find(|stream| stream.index == index)
+                && let Some((_, count)) = counts
+                    .iter_mut()
+                    .find(|(codec_type, _)| *codec_type == stream.codec_type)
+            {
+                *count += 1;
+            }
+        }
+        counts
+    }
+
+    /// Differences between the output and what was asked of ffmpeg.
+    pub fn get_verification_issues(
+        &self,
+        probe: &FFProbeResult,
+        output_probe: &FFProbeResult,
+        output_path: &Path,
+    ) -> Vec<String> {
+        let mut issues = vec![];
+
+        match (
+            probe.format.duration_seconds(),
+            output_probe.format.duration_seconds(),
+        ) {
+            (Some(duration), Some(output_duration))
+                if (duration - output_duration).abs() > self.config.duration_tolerance =>
+            {
+                issues.push(format!(
+                    "output lasts {:.1}s instead of {:.1}s",
+                    output_duration, duration
+                ));
+            }
+            (Some(_), None) => issues.push("output has no duration".into()),
+            _ => {}
+        }
+
+        for (codec_type, expected) in self.get_expected_stream_counts(probe, output_path) {
+            let count = output_probe
+                .streams
+                .iter()
+                .filter(|stream| stream.codec_type == codec_type)
+                .count();
+            if count != expected {
+                issues.push(format!(
+                    "output has {} {} streams instead of {}",
+                    count, codec_type, expected
+                ));
+            }
+        }
+
+        // Copied streams were accepted as they are, the output container may
+        // just report more about them, e.g. a bitrate MKV did not have
+        let output_actions = self.get_output_stream_actions(probe, output_path);
+        for issue in self.validate(output_probe).issues {
+            let is_copied = issue.stream.is_some_and(|index| {
+                output_actions.get(index as usize) == Some(&FFMpegStreamAction::Copy)
+            });
+            if !is_copied {
+                issues.push(format!("output is not valid, {}", issue));
+            }
+        }
+
+        issues
     }
 
-    fn get_tmp_output_path(output_path: &Path) -> PathBuf {
-        let file_name = output_path.file_name().and_then(|s| s.to_str()).unwrap();
-        let folder_name = output_path
-            .parent()
-            .and_then(|p| p.as_os_str().to_str())
-            .unwrap_or(".");
-        if folder_name.len() == 0 {
-            PathBuf::from(format!("{}.part", file_name))
+    /// Probes the temporary output and checks it, and that the sidecars were
+    /// written, before they replace the input.
+    async fn verify(
+        &self,
+        probe: &FFProbeResult,
+        tmp_output_path: &Path,
+        output_path: &Path,
+        sidecars: &[FFMpegSidecar],
+    ) -> Result<()> {
+        let output_probe = ffprobe(tmp_output_path).await?;
+        let mut issues = self.get_verification_issues(probe, &output_probe, output_path);
+        for sidecar in sidecars.iter() {
+            if !fs::try_exists(self.get_tmp_output_path(&sidecar.path))
+                .await
+                .unwrap_or(false)
+            {
+                issues.push(format!(
+                    "sidecar {} was not written",
+                    sidecar.path.display()
+                ));
+            }
+        }
+        if issues.is_empty() {
+            Ok(())
         } else {
-            PathBuf::from(format!("{}/{}.part", folder_name, file_name))
+            Err(Error::Verification(issues))
         }
     }
 
-    pub fn is_stream_valid(&self, stream: &FFProbeResultStream) -> bool {
-        if let Some(codec_name) = &stream.codec_name {
-            match stream.codec_type.as_str() {
-                "video" => {
-                    if !codec_name.eq_ignore_ascii_case("h264") {
-                        return false;
+    /// Describes what will happen to each input stream, in input order. A
+    /// stream may end up in several outputs and so have several actions.
+    pub fn get_stream_actions(
+        &self,
+        probe: &FFProbeResult,
+        output_path: &Path,
+    ) -> Vec<(u8, FFMpegStreamAction)> {
+        let selected_streams = self.get_selected_streams(probe);
+        let burn_stream = self.get_burn_stream(&selected_streams);
+        let sidecars = self.get_sidecars(probe, output_path);
+        let first_video = selected_streams
+            .iter()
+            .find(|stream| stream.codec_type == "video")
+            .map(|stream| stream.index);
+
+        let mut actions = vec![];
+        for stream in probe.streams.iter() {
+            let index = stream.index;
+            let is_selected = selected_streams
+                .iter()
+                .any(|selected| selected.index == index);
+            let mut stream_actions = vec![];
+
+            if is_selected {
+                match stream.codec_type.as_str() {
+                    "video" => {
+                        let burn = if first_video == Some(index) {
+                            burn_stream
+                        } else {
+                            None
+                        };
+                        let filter = self.get_video_filter(stream, burn);
+                        stream_actions.push(FFMpegStreamAction::from_codec(self.get_video_target(
+                            probe,
+                            stream,
+                            filter.is_some(),
+                        )));
+                    }
+                    "audio" if self.is_surround(stream) => {
+                        stream_actions.push(FFMpegStreamAction::from_codec(
+                            self.get_surround_target(stream),
+                        ));
+                        stream_actions.push(FFMpegStreamAction::Downmix(
+                            self.profile.audio_codec.clone(),
+                            self.profile.audio_channels,
+                        ));
                     }
-                    match &stream.bit_rate {
-                        Some(bit_rate_raw) => {
-                            let bit_rate = bit_rate_raw.parse::<u32>().unwrap_or(0);
-                            bit_rate <= self.config.video_maxrate
+                    "audio" => {
+                        stream_actions.push(FFMpegStreamAction::from_codec(
+                            self.get_audio_target(stream),
+                        ));
+                    }
+                    "subtitle" if burn_stream == Some(index) => {
+                        stream_actions.push(FFMpegStreamAction::Burn);
+                    }
+                    "subtitle" => {
+                        if let Some(codec) = self.get_subtitle_target(stream) {
+                            stream_actions.push(FFMpegStreamAction::from_codec(codec));
                         }
-                        _ => true,
                     }
+                    _ => {}
                 }
-                "audio" => {
-                    codec_name.eq_ignore_ascii_case("aac") && stream.channels.unwrap_or(2) <= 2
+
+                for sidecar in sidecars.iter().filter(|sidecar| sidecar.index == index) {
+                    stream_actions.push(FFMpegStreamAction::Extract(sidecar.path.clone()));
                 }
-                "subtitle" => codec_name.eq_ignore_ascii_case("mov_text"),
-                _ => true,
             }
-        } else {
-            true
-        }
-    }
 
-    pub fn is_valid(&self, probe: &FFProbeResult) -> bool {
-        for stream in probe.streams.iter() {
-            if !self.is_stream_valid(&stream) {
-                return false;
+            if stream_actions.is_empty() {
+                stream_actions.push(FFMpegStreamAction::Drop);
             }
+            actions.extend(stream_actions.into_iter().map(|action| (index, action)));
         }
-        true
+        actions
     }
 
+    /// Builds the ffmpeg command, writing to the temporary counterparts of
+    /// `output_path` and of its sidecar files.
     pub fn get_command(&self, probe: &FFProbeResult, output_path: &Path) -> Command {
+        self.get_pass_command(probe, output_path, None, &FFMpegLoudness::new(), None)
+    }
+
+    /// Commands to run in order, two of them for a two-pass encode. `crf`
+    /// overrides the configured CRF, as picked by `search_crf`.
+    pub fn get_commands(
+        &self,
+        probe: &FFProbeResult,
+        output_path: &Path,
+        loudness: &FFMpegLoudness,
+        crf: Option<u8>,
+    ) -> Vec<Command> {
+        if self.is_two_pass(probe) {
+            vec![
+                self.get_pass_command(probe, output_path, Some(1), loudness, crf),
+                self.get_pass_command(probe, output_path, Some(2), loudness, crf),
+            ]
+        } else {
+            vec![self.get_pass_command(probe, output_path, None, loudness, crf)]
+        }
+    }
+
+    /// The first pass of a two-pass encode only analyses the video and
+    /// discards its output.
+    fn get_pass_command(
+        &self,
+        probe: &FFProbeResult,
+        output_path: &Path,
+        pass: Option<u8>,
+        loudness: &FFMpegLoudness,
+        crf: Option<u8>,
+    ) -> Command {
         let maxrate = self.config.video_maxrate;
+        let is_first_pass = pass == Some(1);
         let mut cmd = Command::new("ffmpeg");
         cmd
             // Input
@@ -122,194 +1725,794 @@ impl FFMpeg {
             .arg("-")
             .arg("-nostats")
             .arg("-stats_period")
-            .arg("5")
-            // General
-            .arg("-movflags")
-            .arg("faststart")
-            .arg("-f")
-            .arg("mp4")
-            // Video
-            .arg("-crf")
-            .arg(self.config.crf_level.to_string())
-            .arg("-level")
-            .arg("3.0")
-            .arg("-pix_fmt")
-            .arg("yuv420p")
-            .arg("-maxrate")
-            .arg(maxrate.to_string())
-            .arg("-bufsize")
-            .arg((maxrate * 2).to_string())
-            // Audio
-            .arg("-ac")
-            .arg("2")
-            .arg("-b:a")
-            .arg(self.config.audio_bitrate.to_string());
+            .arg("5");
 
-        for stream in probe.streams.iter() {
-            if let Some(target_codec) = match stream.codec_type.as_str() {
-                "video" => Some("h264"),
-                "audio" => Some("aac"),
-                "subtitle" => {
-                    if let Some(codec_name) = &stream.codec_name {
-                        match codec_name.as_str() {
-                            "dvbsub" | "dvdsub" | "pgssub" | "xsub" => None,
-                            _ => Some("mov_text"),
+        if self.profile.supports_faststart() && !is_first_pass {
+            cmd.arg("-movflags").arg("faststart");
+        }
+
+        let video_codec = self.video_codec();
+
+        // General
+        cmd.arg("-f").arg(if is_first_pass {
+            "null"
+        } else {
+            &self.profile.format
+        });
+
+        // Video
+        let target_bitrate = self.get_target_video_bitrate(probe);
+        match target_bitrate {
+            Some(bitrate) => {
+                cmd.arg("-b:v").arg(bitrate.to_string());
+            }
+            None => {
+                cmd.arg("-crf")
+                    .arg(crf.unwrap_or_else(|| self.get_crf_level()).to_string());
+            }
+        }
+
+        if let Some(pass) = pass {
+            cmd.arg("-pass")
+                .arg(pass.to_string())
+                .arg("-passlogfile")
+                .arg(self.get_passlog_path(output_path));
+        }
+
+        if let Some(preset) = &self.config.preset {
+            cmd.arg("-preset").arg(match video_codec {
+                Some(codec) => codec.preset(preset),
+                None => preset.clone(),
+            });
+        }
+
+        let video_stream = probe
+            .streams
+            .iter()
+            .find(|stream| stream.codec_type == "video");
+
+        if video_codec == Some(VideoCodec::H264)
+            && let Some(stream) = video_stream
+        {
+            let max_bitrate = target_bitrate.unwrap_or(maxrate);
+            cmd.arg("-profile:v")
+                .arg(self.get_h264_profile(stream))
+                .arg("-level:v")
+                .arg(format_h264_level(self.get_h264_level(stream, max_bitrate)));
+        }
+
+        if self.profile.supports_faststart()
+            && let Some(tag) = video_codec.and_then(|codec| codec.mp4_tag())
+        {
+            cmd.arg("-tag:v").arg(tag);
+        }
+
+        cmd.arg("-pix_fmt").arg(self.get_pix_fmt(video_stream));
+        if let Some(stream) = video_stream
+            && self.profile.hdr
+            && stream.is_hdr()
+        {
+            // Tag the encode like its source so players switch to HDR
+            for (arg, value) in [
+                ("-color_primaries", &stream.color_primaries),
+                ("-color_trc", &stream.color_transfer),
+                ("-colorspace", &stream.color_space),
+            ] {
+                if let Some(value) = value {
+                    cmd.arg(arg).arg(value);
+                }
+            }
+        }
+        if target_bitrate.is_none() {
+            cmd.arg("-maxrate")
+                .arg(maxrate.to_string())
+                .arg("-bufsize")
+                .arg((maxrate * 2).to_string());
+        }
+
+        let selected_streams = self.get_selected_streams(probe);
+        let default_audio = self.get_default_stream(
+            &selected_streams,
+            "audio",
+            &self.config.default_audio_language,
+        );
+        let default_subtitle = self.get_default_stream(
+            &selected_streams,
+            "subtitle",
+            &self.config.default_subtitle_language,
+        );
+
+        let burn_stream = self.get_burn_stream(&selected_streams);
+
+        let mut video_index = 0;
+        let mut audio_index = 0;
+        let mut subtitle_index = 0;
+
+        for stream in selected_streams {
+            if is_first_pass && stream.codec_type != "video" {
+                continue;
+            }
+            match stream.codec_type.as_str() {
+                "video" => {
+                    let filter = self.get_video_filter(
+                        stream,
+                        if video_index == 0 { burn_stream } else { None },
+                    );
+                    let codec = self.get_video_target(probe, stream, filter.is_some());
+                    match filter {
+                        Some(filter) => {
+                            cmd.arg("-filter_complex")
+                                .arg(filter)
+                                .arg("-map")
+                                .arg(format!("[v{}]", stream.index));
+                        }
+                        None => {
+                            cmd.arg("-map").arg(format!("0:{}", stream.index));
                         }
-                    } else {
-                        None
                     }
+                    cmd.arg(format!("-c:v:{}", video_index)).arg(codec);
+                    video_index += 1;
                 }
-                _ => None,
-            } {
-                let codec = if self.is_stream_valid(&stream) {
-                    "copy"
-                } else {
-                    target_codec
-                };
+                "audio" => {
+                    let channels = stream.channels.unwrap_or(2);
+                    if self.is_surround(stream) {
+                        // Keep the surround track and add a downmixed one next to it
+                        let codec = self.get_surround_target(stream);
+                        cmd.arg("-map")
+                            .arg(format!("0:{}", stream.index))
+                            .arg(format!("-c:a:{}", audio_index))
+                            .arg(codec);
+                        let mut surround_channels = channels;
+                        if codec != "copy" {
+                            if let Some(max) = max_audio_channels(codec)
+                                && channels > max
+                            {
+                                surround_channels = max;
+                                cmd.arg(format!("-ac:a:{}", audio_index))
+                                    .arg(max.to_string());
+                            }
+                            cmd.arg(format!("-b:a:{}", audio_index))
+                                .arg(self.config.surround_bitrate.to_string());
+                            if let Some(filter) = self.get_audio_filter(stream, false, loudness) {
+                                cmd.arg(format!("-filter:a:{}", audio_index)).arg(filter);
+                            }
+                        }
+                        cmd.arg(format!("-metadata:s:a:{}", audio_index))
+                            .arg(format!(
+                                "title={}",
+                                Self::get_channels_title(surround_channels)
+                            ));
+                        if default_audio.is_some() {
+                            cmd.arg(format!("-disposition:a:{}", audio_index)).arg(
+                                Self::get_disposition(stream, default_audio == Some(stream.index)),
+                            );
+                        }
+                        audio_index += 1;
 
-                cmd
-                    // Stream
-                    .arg("-map")
-                    .arg(format!("0:{}", stream.index))
-                    .arg(format!("-c:{}", stream.index))
-                    .arg(codec);
+                        cmd.arg("-map")
+                            .arg(format!("0:{}", stream.index))
+                            .arg(format!("-c:a:{}", audio_index))
+                            .arg(&self.profile.audio_codec)
+                            .arg(format!("-ac:a:{}", audio_index))
+                            .arg(self.profile.audio_channels.to_string())
+                            .arg(format!("-b:a:{}", audio_index))
+                            .arg(self.config.audio_bitrate.to_string())
+                            .arg(format!("-metadata:s:a:{}", audio_index))
+                            .arg(format!(
+                                "title={}",
+                                Self::get_channels_title(self.profile.audio_channels)
+                            ));
+                        if let Some(filter) = self.get_audio_filter(stream, true, loudness) {
+                            cmd.arg(format!("-filter:a:{}", audio_index)).arg(filter);
+                        }
+                        if default_audio.is_some() {
+                            cmd.arg(format!("-disposition:a:{}", audio_index))
+                                .arg(Self::get_disposition(stream, false));
+                        }
+                        audio_index += 1;
+                    } else {
+                        let codec = self.get_audio_target(stream);
+                        cmd.arg("-map")
+                            .arg(format!("0:{}", stream.index))
+                            .arg(format!("-c:a:{}", audio_index))
+                            .arg(codec);
+                        if codec != "copy" {
+                            cmd.arg(format!("-ac:a:{}", audio_index))
+                                .arg(channels.min(self.profile.audio_channels).to_string())
+                                .arg(format!("-b:a:{}", audio_index))
+                                .arg(self.config.audio_bitrate.to_string());
+                            let downmix = channels > self.profile.audio_channels;
+                            if let Some(filter) = self.get_audio_filter(stream, downmix, loudness) {
+                                cmd.arg(format!("-filter:a:{}", audio_index)).arg(filter);
+                            }
+                        }
+                        if default_audio.is_some() {
+                            cmd.arg(format!("-disposition:a:{}", audio_index)).arg(
+                                Self::get_disposition(stream, default_audio == Some(stream.index)),
+                            );
+                        }
+                        audio_index += 1;
+                    }
+                }
+                "subtitle" => {
+                    if let Some(codec) = self.get_subtitle_target(stream) {
+                        cmd.arg("-map")
+                            .arg(format!("0:{}", stream.index))
+                            .arg(format!("-c:s:{}", subtitle_index))
+                            .arg(codec);
+                        if default_subtitle.is_some() {
+                            cmd.arg(format!("-disposition:s:{}", subtitle_index)).arg(
+                                Self::get_disposition(
+                                    stream,
+                                    default_subtitle == Some(stream.index),
+                                ),
+                            );
+                        }
+                        subtitle_index += 1;
+                    }
+                }
+                _ => {}
             }
         }
 
+        if is_first_pass {
+            cmd.arg(if cfg!(windows) { "NUL" } else { "/dev/null" });
+            return cmd;
+        }
+
         cmd
             // Output
-            .arg(output_path.to_str().unwrap());
+            .arg(self.get_tmp_output_path(output_path));
+
+        for sidecar in self.get_sidecars(probe, output_path) {
+            cmd.arg("-map")
+                .arg(format!("0:{}", sidecar.index))
+                .arg("-c")
+                .arg(sidecar.codec)
+                .arg("-f")
+                .arg(sidecar.format)
+                .arg(self.get_tmp_output_path(&sidecar.path));
+        }
 
         cmd
     }
 
+    pub fn cancel_token(&self) -> FFMpegCancelToken {
+        self.cancel_token.clone()
+    }
+
+    pub fn set_cancel_token(&mut self, cancel_token: FFMpegCancelToken) {
+        self.cancel_token = cancel_token;
+    }
+
     pub fn subscribe(&self) -> broadcast::Receiver<FFMpegEvent> {
         self.tx.subscribe()
     }
 
-    pub async fn move_srt_files(
+    /// Files next to the input sharing its stem (subtitles, nfo...) and the
+    /// path they should be moved to so they follow the output.
+    pub async fn get_srt_files(
         input_path: &Path,
         output_path: &Path,
-        keep_original: bool,
-    ) -> io::Result<()> {
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let mut files = vec![];
         if let Some(input_folder_path) = input_path.parent()
             && let Some(output_folder_path) = output_path.parent()
             && let Some(input_stem) = input_path.file_stem().and_then(|s| s.to_str())
             && let Some(output_stem) = output_path.file_stem().and_then(|s| s.to_str())
         {
-            let mut read_dir = fs::read_dir(&input_folder_path).await?;
-            while let Some(entry) = read_dir.next_entry().await? {
+            let mut read_dir = fs::read_dir(&input_folder_path)
+                .await
+                .step("read folder", input_folder_path)?;
+            while let Some(entry) = read_dir
+                .next_entry()
+                .await
+                .step("read folder", input_folder_path)?
+            {
                 let entry_path = entry.path();
                 if entry_path == input_path || entry_path == output_path {
                     continue;
                 }
                 if let Some(entry_name) = entry_path.file_name().and_then(|s| s.to_str())
                     && entry_name.starts_with(input_stem)
+                    && !entry_name.starts_with(output_stem)
                 {
                     let target_path = output_folder_path.join(format!(
                         "{}{}",
                         output_stem,
                         &entry_name[input_stem.len()..]
                     ));
-                    if keep_original {
-                        fs::copy(entry_path, target_path).await?;
-                    } else {
-                        fs::rename(entry_path, target_path).await?;
+                    files.push((entry_path, target_path));
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    pub async fn move_srt_files(
+        input_path: &Path,
+        output_path: &Path,
+        keep_original: bool,
+    ) -> Result<()> {
+        for (entry_path, target_path) in Self::get_srt_files(input_path, output_path).await? {
+            if keep_original {
+                fs::copy(&entry_path, target_path)
+                    .await
+                    .step("copy", &entry_path)?;
+            } else {
+                fs::rename(&entry_path, target_path)
+                    .await
+                    .step("move", &entry_path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn format_command(cmd: &Command) -> String {
+        let std_cmd = cmd.as_std();
+        format!(
+            "{} {}",
+            std_cmd.get_program().to_string_lossy(),
+            std_cmd
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    }
+
+    async fn read_progress(
+        &self,
+        stdout: ChildStdout,
+        context: &FFMpegContext,
+        pass: u8,
+        passes: u8,
+    ) -> Result<()> {
+        let reader = BufReader::new(stdout);
+        let mut lines = reader.lines();
+
+        let mut progress = FFMpegProgress {
+            out_time_us: 0,
+            speed: "0x".into(),
+            pass,
+            passes,
+        };
+
+        while let Some(line) = lines
+            .next_line()
+            .await
+            .step("read the progress of", Path::new(&context.input_path))?
+        {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+
+            match key {
+                "speed" => {
+                    progress.speed = String::from(value);
+                }
+                "out_time_ms" => {
+                    if let Ok(out_time_us) = value.parse() {
+                        progress.out_time_us = out_time_us;
                     }
                 }
+                "progress" => {
+                    self.emit(FFMpegEvent::PROGRESS(context.clone(), progress.clone()));
+                }
+                _ => (),
             }
         }
 
         Ok(())
     }
 
-    pub async fn transcode(&mut self, probe: &FFProbeResult, output_path: &Path) -> io::Result<()> {
-        let tmp_output_path = Self::get_tmp_output_path(output_path);
-        let mut binding = self.get_command(probe, &tmp_output_path);
-        let cmd = binding.stdout(Stdio::piped());
+    /// Keeps the last `max_lines` lines ffmpeg prints to stderr.
+    async fn read_stderr(stderr: ChildStderr, max_lines: usize) -> Vec<String> {
+        let mut lines = BufReader::new(stderr).lines();
+        let mut last_lines = VecDeque::new();
+        while let Ok(Some(line)) = lines.next_line().await {
+            last_lines.push_back(line);
+            if last_lines.len() > max_lines {
+                last_lines.pop_front();
+            }
+        }
+        last_lines.into()
+    }
+
+    /// Runs the commands one after the other, stopping at the first failure.
+    async fn run_commands(&self, commands: Vec<Command>, context: &FFMpegContext) -> Result<()> {
+        let input_path = Path::new(&context.input_path);
+        let passes = commands.len() as u8;
+        for (pass, mut cmd) in (1..).zip(commands) {
+            let mut child = cmd
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .step("start ffmpeg on", input_path)?;
 
-        let mut child = cmd.spawn()?;
-        let std_cmd = cmd.as_std();
+            // Read on the side so ffmpeg never blocks on a full stderr pipe
+            let stderr_task = child
+                .stderr
+                .take()
+                .map(|stderr| tokio::spawn(Self::read_stderr(stderr, self.config.stderr_lines)));
+
+            let cancelled = match child.stdout.take() {
+                Some(stdout) => tokio::select! {
+                    _ = self.cancel_token.0.notified() => true,
+                    result = self.read_progress(stdout, context, pass, passes) => {
+                        result?;
+                        false
+                    }
+                },
+                None => false,
+            };
+
+            if cancelled {
+                child.kill().await.step("stop ffmpeg on", input_path)?;
+                return Err(Error::Cancelled);
+            }
+
+            let status = child.wait().await.step("wait for ffmpeg on", input_path)?;
+            if !status.success() {
+                let stderr = match stderr_task {
+                    Some(task) => task.await.unwrap_or_default(),
+                    None => vec![],
+                };
+                return Err(Error::Encoder {
+                    code: status.code(),
+                    stderr,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_tmp_outputs(&self, output_path: &Path, sidecars: &[FFMpegSidecar]) {
+        let _ = fs::remove_file(self.get_tmp_output_path(output_path)).await;
+        for sidecar in sidecars.iter() {
+            let _ = fs::remove_file(self.get_tmp_output_path(&sidecar.path)).await;
+        }
+    }
+
+    pub async fn transcode(
+        &mut self,
+        probe: &FFProbeResult,
+        output_path: &Path,
+    ) -> Result<Option<FFMpegQuality>> {
+        let tmp_output_path = self.get_tmp_output_path(output_path);
+        let sidecars = self.get_sidecars(probe, output_path);
+
+        self.check_free_space(probe, output_path)?;
+
+        let loudness = tokio::select! {
+            _ = self.cancel_token.0.notified() => return Err(Error::Cancelled),
+            loudness = self.measure_loudness(probe) => loudness?,
+        };
+        let crf_search = tokio::select! {
+            _ = self.cancel_token.0.notified() => return Err(Error::Cancelled),
+            crf_search = self.search_crf(probe, output_path) => crf_search?,
+        };
+        let crf = crf_search.as_ref().and_then(|search| search.crf);
+        let commands = self.get_commands(probe, output_path, &loudness, crf);
 
         let context = FFMpegContext {
             probe: probe.clone(),
-            command: format!(
-                "{} {}",
-                std_cmd.get_program().to_string_lossy(),
-                std_cmd
-                    .get_args()
-                    .map(|arg| arg.to_string_lossy().into_owned())
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            ),
+            report: self.validate(probe),
+            command: commands
+                .iter()
+                .map(Self::format_command)
+                .collect::<Vec<_>>()
+                .join("\n"),
             input_path: probe.format.filename.clone(),
             output_path: output_path.display().to_string(),
         };
 
         self.emit(FFMpegEvent::START(context.clone()));
 
-        if let Some(stdout) = child.stdout.as_mut() {
-            let reader = BufReader::new(stdout);
-            let mut lines = reader.lines();
-
-            let mut progress = FFMpegProgress {
-                out_time_us: 0,
-                speed: "0x".into(),
+        if let Some(search) = crf_search
+            && search.crf.is_none()
+        {
+            let metric = self.config.quality_metric.unwrap_or(QualityMetric::Vmaf);
+            let reason = match search.error {
+                Some(error) => format!("the CRF search failed, {}", error),
+                None => format!(
+                    "no CRF reaches {} {}, the best sample scored {:.2}",
+                    metric.name(),
+                    self.config.target_quality.unwrap_or_default(),
+                    search.best_score
+                ),
             };
+            self.emit(FFMpegEvent::WARNING(
+                context.clone(),
+                format!("Encoding at CRF {}, {}", self.get_crf_level(), reason),
+            ));
+        }
 
-            while let Some(line) = lines.next_line().await.expect("Failed to read output") {
-                let parts = line.split("=").collect::<Vec<&str>>();
-
-                let key = parts[0];
-                let value = parts[1].trim();
+        let result = self.run_commands(commands, &context).await;
+        self.remove_passlog_files(output_path).await;
 
-                match key {
-                    "speed" => {
-                        progress.speed = String::from(value);
-                    }
-                    "out_time_ms" => {
-                        if let Ok(out_time_us) = value.parse() {
-                            progress.out_time_us = out_time_us;
-                        }
-                    }
-                    "progress" => {
-                        self.emit(FFMpegEvent::PROGRESS(context.clone(), progress.clone()));
-                    }
-                    _ => (),
-                }
+        match result {
+            Err(Error::Cancelled) => {
+                self.remove_tmp_outputs(output_path, &sidecars).await;
+                self.emit(FFMpegEvent::CANCELLED(context));
+                return Err(Error::Cancelled);
+            }
+            Err(error) => {
+                self.emit(FFMpegEvent::ERROR(context, FFMpegFailure::from(&error)));
+                return Err(error);
             }
+            Ok(()) => {}
         }
 
-        match child.wait().await {
-            Ok(status) => {
-                if !status.success() {
-                    self.emit(FFMpegEvent::ERROR(context));
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        format!("ffmpeg exited with status: {:?}", status.code()),
-                    ));
-                }
+        // The output is left in place to be looked at when it is rejected
+        if let Err(error) = self
+            .verify(probe, &tmp_output_path, output_path, &sidecars)
+            .await
+        {
+            self.emit(FFMpegEvent::ERROR(context, FFMpegFailure::from(&error)));
+            return Err(error);
+        }
+
+        // Measured while the input is still around to compare to
+        let quality = tokio::select! {
+            _ = self.cancel_token.0.notified() => Err(Error::Cancelled),
+            quality = self.measure_quality(probe, &tmp_output_path, output_path) => quality,
+        };
+        let quality = match quality {
+            Err(Error::Cancelled) => {
+                self.remove_tmp_outputs(output_path, &sidecars).await;
+                self.emit(FFMpegEvent::CANCELLED(context));
+                return Err(Error::Cancelled);
             }
+            // The quality is only reported, the encode is kept regardless
             Err(error) => {
-                self.emit(FFMpegEvent::ERROR(context));
-                return Err(error);
+                self.emit(FFMpegEvent::WARNING(
+                    context.clone(),
+                    format!("Quality was not measured: {}", error),
+                ));
+                None
             }
-        }
+            Ok(quality) => quality,
+        };
+
+        move_file(&tmp_output_path, output_path)
+            .await
+            .step("move the output to", output_path)?;
 
-        fs::rename(tmp_output_path, output_path).await?;
+        for sidecar in sidecars.iter() {
+            move_file(&self.get_tmp_output_path(&sidecar.path), &sidecar.path)
+                .await
+                .step("move the sidecar to", &sidecar.path)?;
+        }
 
+        // Everything is in place before the input goes away
         let input_path = PathBuf::from(probe.format.filename.as_str());
+        Self::move_srt_files(&input_path, output_path, self.config.keep_input_file).await?;
         if !self.config.keep_input_file && input_path != output_path {
-            fs::remove_file(&input_path).await?;
+            fs::remove_file(&input_path)
+                .await
+                .step("remove", &input_path)?;
         }
-        Self::move_srt_files(&input_path, output_path, self.config.keep_input_file).await?;
 
-        self.emit(FFMpegEvent::DONE(context));
+        self.emit(FFMpegEvent::DONE(context, quality));
 
-        Ok(())
+        Ok(quality)
     }
 
     pub fn dispose(&mut self) {
         self.emit(FFMpegEvent::CLOSE());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    const LOUDNORM_STDERR: &str = r#"Input #0, matroska,webm, from 'movie.mkv':
+  Duration: 00:02:00.00, start: 0.000000, bitrate: 5000 kb/s
+Stream mapping:
+  Stream #0:1 -> #0:0 (aac (native) -> pcm_s16le (native))
+[Parsed_loudnorm_1 @ 0x5581b2a0e2c0]
+{
+	"input_i" : "-27.61",
+	"input_tp" : "-4.47",
+	"input_lra" : "18.06",
+	"input_thresh" : "-39.20",
+	"output_i" : "-16.58",
+	"output_tp" : "-1.50",
+	"output_lra" : "14.78",
+	"output_thresh" : "-27.71",
+	"normalization_type" : "dynamic",
+	"target_offset" : "0.58"
+}
+[out#0/null @ 0x5581b2a0f440] video:0KiB audio:22500KiB subtitle:0KiB other streams:0KiB global headers:0KiB muxing overhead: unknown
+size=N/A time=00:02:00.00 bitrate=N/A speed= 912x
+"#;
+
+    fn probe(filename: &str, video_codec: &str, video_bit_rate: Option<&str>) -> FFProbeResult {
+        serde_json::from_value(serde_json::json!({
+            "streams": [
+                {
+                    "index": 0,
+                    "codec_name": video_codec,
+                    "codec_type": "video",
+                    "bit_rate": video_bit_rate,
+                    "width": 1920,
+                    "height": 1080,
+                    "pix_fmt": "yuv420p",
+                    "profile": "High",
+                    "level": 40,
+                    "r_frame_rate": "24000/1001",
+                    "avg_frame_rate": "24000/1001"
+                },
+                {
+                    "index": 1,
+                    "codec_name": "aac",
+                    "codec_type": "audio",
+                    "channels": 2,
+                    "bit_rate": "128000",
+                    "tags": { "language": "eng" }
+                }
+            ],
+            "format": {
+                "filename": filename,
+                "format_name": "matroska,webm",
+                "format_long_name": "Matroska / WebM",
+                "duration": "120.000000",
+                "size": "60000000",
+                "bit_rate": "4000000"
+            }
+        }))
+        .unwrap()
+    }
+
+    fn ffmpeg() -> FFMpeg {
+        FFMpeg::new(&FFMpegConfig::parse_from(["test"]), &Profile::default())
+    }
+
+    #[test]
+    fn verification_ignores_copied_streams() {
+        let ffmpeg = ffmpeg();
+        // MKV had no stream bitrate, the mp4 reports one above the maxrate
+        let input = probe("movie.mkv", "h264", None);
+        let output = probe("movie.mp4", "h264", Some("6000000"));
+        let output_path = Path::new("movie.mp4");
+        assert_eq!(
+            ffmpeg.get_stream_actions(&input, output_path)[0],
+            (0, FFMpegStreamAction::Copy)
+        );
+        assert!(
+            ffmpeg
+                .get_verification_issues(&input, &output, output_path)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn verification_checks_encoded_streams() {
+        let ffmpeg = ffmpeg();
+        let input = probe("movie.mkv", "mpeg4", None);
+        let output = probe("movie.mp4", "h264", Some("6000000"));
+        let issues = ffmpeg.get_verification_issues(&input, &output, Path::new("movie.mp4"));
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].starts_with("output is not valid"));
+    }
+
+    #[test]
+    fn quality_score_is_parsed_from_filter_logs() {
+        let stats = "\n[out#0/null @ 0x55f5c6c10d80] video:2KiB audio:0KiB subtitle:0KiB\nframe=  240 fps= 48 q=-0.0 size=N/A time=00:00:10.00 bitrate=N/A speed=   2x\n";
+        let vmaf = "[Parsed_libvmaf_4 @ 0x55f5c6c0e0c0] VMAF score: 94.872938";
+        let ssim = "[Parsed_ssim_4 @ 0x5608a1d3c0c0] SSIM Y:0.987654 (19.082436) U:0.993210 (21.678901) V:0.992345 (21.163579) All:0.989456 (19.770138)";
+        let psnr = "[Parsed_psnr_4 @ 0x5608a1d3c0c0] PSNR y:42.123456 u:45.678901 v:46.012345 average:43.210987 min:38.765432 max:50.123456";
+        for (metric, line, score) in [
+            (QualityMetric::Vmaf, vmaf, 94.872938),
+            (QualityMetric::Ssim, ssim, 0.989456),
+            (QualityMetric::Psnr, psnr, 43.210987),
+        ] {
+            let stderr = format!("{}{}", line, stats);
+            assert_eq!(FFMpeg::parse_quality_score(metric, &stderr), Some(score));
+        }
+        assert_eq!(
+            FFMpeg::parse_quality_score(QualityMetric::Vmaf, stats),
+            None
+        );
+    }
+
+    #[test]
+    fn quality_segments_are_spread_over_long_videos() {
+        let ffmpeg = ffmpeg();
+        let probe = probe("movie.mkv", "h264", None);
+        assert_eq!(
+            ffmpeg.get_quality_segments(&probe, 3),
+            vec![Some((15.0, 10.0)), Some((55.0, 10.0)), Some((95.0, 10.0))]
+        );
+        assert_eq!(ffmpeg.get_quality_segments(&probe, 0), vec![None]);
+    }
+
+    #[test]
+    fn quality_segments_cover_short_videos_whole() {
+        let ffmpeg = ffmpeg();
+        let mut probe = probe("movie.mkv", "h264", None);
+        probe.format.duration = "25.000000".into();
+        assert_eq!(ffmpeg.get_quality_segments(&probe, 3), vec![None]);
+        probe.format.duration = "N/A".into();
+        assert_eq!(ffmpeg.get_quality_segments(&probe, 3), vec![None]);
+    }
+
+    #[tokio::test]
+    async fn crf_search_picks_highest_crf_reaching_target() {
+        let search = FFMpegCrfSearch::bisect((16, 32), 80.0, async |crf| Ok(100.0 - crf as f64))
+            .await
+            .unwrap();
+        assert_eq!(search.crf, Some(20));
+        assert_eq!(search.best_score, 81.0);
+    }
+
+    #[tokio::test]
+    async fn crf_search_tracks_best_score_when_no_crf_reaches_target() {
+        let mut tried = vec![];
+        let search = FFMpegCrfSearch::bisect((20, 36), 90.0, |crf| {
+            tried.push(crf);
+            async move { Ok(80.0 - crf as f64) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(search.crf, None);
+        assert_eq!(search.best_score, 60.0);
+        assert_eq!(tried, vec![28, 23, 21, 20]);
+    }
+
+    #[tokio::test]
+    async fn crf_search_stays_in_range_when_every_crf_reaches_target() {
+        let mut tried = vec![];
+        let search = FFMpegCrfSearch::bisect((20, 36), 90.0, |crf| {
+            tried.push(crf);
+            async { Ok(100.0) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(search.crf, Some(36));
+        assert_eq!(tried, vec![28, 32, 34, 35, 36]);
+    }
+
+    #[tokio::test]
+    async fn crf_search_stops_at_first_error() {
+        let mut tried = 0;
+        let search = FFMpegCrfSearch::bisect((16, 32), 80.0, |_| {
+            tried += 1;
+            async { Err(Error::Quality("VMAF printed no score".into())) }
+        })
+        .await;
+        assert!(matches!(search, Err(Error::Quality(_))));
+        assert_eq!(tried, 1);
+    }
+
+    #[test]
+    fn crf_search_range_follows_video_codec() {
+        assert_eq!(ffmpeg().get_crf_search_range(), (16, 32));
+        assert_eq!(VideoCodec::Hevc.crf_search_range(), (20, 36));
+        assert_eq!(VideoCodec::Av1.crf_search_range(), (20, 50));
+    }
+
+    #[test]
+    fn loudness_measurement_ignores_trailing_stats() {
+        let measurement = FFMpegLoudnessMeasurement::parse(LOUDNORM_STDERR).unwrap();
+        assert_eq!(measurement.input_i, "-27.61");
+        assert_eq!(measurement.input_tp, "-4.47");
+        assert_eq!(measurement.input_lra, "18.06");
+        assert_eq!(measurement.input_thresh, "-39.20");
+        assert_eq!(measurement.target_offset, "0.58");
+    }
+
+    #[test]
+    fn loudness_measurement_is_required() {
+        assert!(FFMpegLoudnessMeasurement::parse("size=N/A time=00:02:00.00").is_err());
+        assert!(FFMpegLoudnessMeasurement::parse("{\"input_i\" : \"-27.61\"}").is_err());
+    }
+}
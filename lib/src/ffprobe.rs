@@ -1,18 +1,114 @@
-use std::{
-    io::{self, Error, ErrorKind},
-    path::Path,
-};
+use std::path::Path;
 
 use serde::{Deserialize, Serialize};
 use tokio::process::Command;
 
+use crate::error::{Error, IoResultExt, Result};
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct FFProbeResultStreamTags {
+    pub language: Option<String>,
+    pub title: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct FFProbeResultStreamDisposition {
+    pub default: u8,
+    pub forced: u8,
+    pub comment: u8,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FFProbeResultStream {
     pub index: u8,
     pub codec_name: Option<String>,
     pub codec_type: String,
     pub channels: Option<u8>,
+    pub channel_layout: Option<String>,
+    pub sample_rate: Option<String>,
     pub bit_rate: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub pix_fmt: Option<String>,
+    pub color_transfer: Option<String>,
+    pub color_primaries: Option<String>,
+    pub color_space: Option<String>,
+    pub profile: Option<String>,
+    pub level: Option<i32>,
+    pub r_frame_rate: Option<String>,
+    pub avg_frame_rate: Option<String>,
+    #[serde(default)]
+    pub tags: FFProbeResultStreamTags,
+    #[serde(default)]
+    pub disposition: FFProbeResultStreamDisposition,
+}
+
+impl FFProbeResultStream {
+    /// Language tag of the stream, `und` when it is missing.
+    pub fn language(&self) -> &str {
+        self.tags.language.as_deref().unwrap_or("und")
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.disposition.default != 0
+    }
+
+    pub fn is_forced(&self) -> bool {
+        self.disposition.forced != 0
+    }
+
+    /// Frames per second, from the average rate and falling back to the
+    /// base one for variable frame rate streams.
+    pub fn frame_rate(&self) -> Option<f64> {
+        let parse = |rate: &Option<String>| {
+            let (num, den) = rate.as_deref()?.split_once('/')?;
+            let num = num.parse::<f64>().ok()?;
+            let den = den.parse::<f64>().ok()?;
+            if num > 0.0 && den > 0.0 {
+                Some(num / den)
+            } else {
+                None
+            }
+        };
+        parse(&self.avg_frame_rate).or_else(|| parse(&self.r_frame_rate))
+    }
+
+    /// PQ (HDR10, Dolby Vision) or HLG video.
+    pub fn is_hdr(&self) -> bool {
+        self.codec_type == "video"
+            && matches!(
+                self.color_transfer.as_deref(),
+                Some("smpte2084" | "arib-std-b67")
+            )
+    }
+
+    /// Bitmap subtitles (PGS, VobSub, DVB, XSUB) that can't be converted to
+    /// a text format.
+    pub fn is_image_subtitle(&self) -> bool {
+        self.codec_type == "subtitle"
+            && matches!(
+                self.codec_name.as_deref(),
+                Some(
+                    "hdmv_pgs_subtitle"
+                        | "pgssub"
+                        | "dvd_subtitle"
+                        | "dvdsub"
+                        | "dvb_subtitle"
+                        | "dvbsub"
+                        | "xsub"
+                )
+            )
+    }
+
+    pub fn is_commentary(&self) -> bool {
+        self.disposition.comment != 0
+            || self
+                .tags
+                .title
+                .as_ref()
+                .is_some_and(|title| title.to_lowercase().contains("commentary"))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -21,6 +117,15 @@ pub struct FFProbeResultFormat {
     pub format_name: String,
     pub format_long_name: String,
     pub duration: String,
+    pub size: Option<String>,
+    pub bit_rate: Option<String>,
+}
+
+impl FFProbeResultFormat {
+    /// Duration in seconds, `None` when ffprobe could not tell.
+    pub fn duration_seconds(&self) -> Option<f64> {
+        self.duration.parse().ok()
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -29,26 +134,37 @@ pub struct FFProbeResult {
     pub format: FFProbeResultFormat,
 }
 
-pub async fn ffprobe(path: &Path) -> io::Result<FFProbeResult> {
+pub async fn ffprobe(path: &Path) -> Result<FFProbeResult> {
+    // The probed file name is kept as a string to be handed back to ffmpeg
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| Error::InvalidPath(path.to_path_buf()))?;
+
     let output = Command::new("ffprobe")
         .arg("-v")
-        .arg("quiet")
+        .arg("error")
         .arg("-print_format")
         .arg("json")
         .arg("-show_format")
         .arg("-show_streams")
-        .arg(path.to_str().unwrap())
+        .arg(path_str)
         .output()
-        .await?;
+        .await
+        .step("run ffprobe on", path)?;
 
     if !output.status.success() {
-        return Err(Error::new(
-            ErrorKind::Other,
-            format!("ffprobe exited with status: {:?}", output.status.code()),
-        ));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Probe {
+            path: path.to_path_buf(),
+            reason: match stderr.trim() {
+                "" => format!("ffprobe exited with status: {:?}", output.status.code()),
+                stderr => stderr.into(),
+            },
+        });
     }
 
-    let result: FFProbeResult = serde_json::from_slice(output.stdout.as_slice())?;
-
-    Ok(result)
+    serde_json::from_slice(output.stdout.as_slice()).map_err(|e| Error::Probe {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    })
 }
@@ -1,46 +1,173 @@
 use std::{
     fs,
-    io::{self},
     path::{Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 
 use async_recursion::async_recursion;
 use tokio::fs::read_dir;
 
+use crate::{
+    config::ScanConfig,
+    error::{Error, IoResultExt, Result},
+    ffprobe::ffprobe,
+    profile::Profile,
+};
+
+pub mod codec;
 pub mod config;
 pub mod discord;
+pub mod error;
 pub mod ffmpeg;
 pub mod ffprobe;
 pub mod log;
+pub mod profile;
 pub mod utils;
+pub mod validation;
 
-const EXTENSIONS: &[&str] = &["mp4"];
+async fn is_movie_file(file_path: &Path, config: &ScanConfig) -> bool {
+    match file_path.extension() {
+        Some(file_extension) => config
+            .extensions
+            .iter()
+            .any(|ext| file_extension.eq_ignore_ascii_case(ext)),
+        None if config.probe_extensionless => match ffprobe(file_path).await {
+            Ok(probe) => {
+                probe.format.duration.parse::<f64>().unwrap_or(0.0) > 0.0
+                    && probe
+                        .streams
+                        .iter()
+                        .any(|stream| stream.codec_type == "video")
+            }
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
 
 #[async_recursion]
-pub async fn list_movie_files(path: &Path, recursive: &bool) -> Result<Vec<PathBuf>, io::Error> {
+pub async fn list_movie_files(
+    path: &Path,
+    recursive: &bool,
+    config: &ScanConfig,
+) -> Result<Vec<PathBuf>> {
     let mut movie_files = Vec::<PathBuf>::new();
 
-    let mut read_dir = read_dir(path).await?;
+    let mut read_dir = read_dir(path).await.step("read folder", path)?;
 
-    while let Some(entry) = read_dir.next_entry().await? {
-        let file_type: fs::FileType = entry.file_type().await?;
+    while let Some(entry) = read_dir.next_entry().await.step("read folder", path)? {
+        let file_type: fs::FileType = entry.file_type().await.step("read", &entry.path())?;
         let file_path = entry.path();
 
         if file_type.is_file() {
-            if let Some(file_extension) = file_path.extension() {
-                let file_extension = file_extension.to_str().unwrap();
-                if EXTENSIONS
-                    .iter()
-                    .any(|ext| file_extension.eq_ignore_ascii_case(ext))
-                {
-                    movie_files.push(file_path);
-                }
+            if is_movie_file(&file_path, config).await {
+                movie_files.push(file_path);
             }
         } else if file_type.is_dir() && *recursive {
-            let children = list_movie_files(&file_path, recursive).await?;
+            let children = list_movie_files(&file_path, recursive, config).await?;
             movie_files.extend(children);
         }
     }
 
     Ok(movie_files)
 }
+
+/// Endings of the temporary outputs of a transcode: the `.part` files and
+/// x264's two-pass logs.
+const TMP_FILE_ENDINGS: &[&str] = &[".part", ".passlog-0.log", ".passlog-0.log.mbtree"];
+
+/// Temporary outputs written by a transcode to the given profile. Outputs,
+/// their sidecars and samples all have the profile's suffix in their name,
+/// which tells them apart from other tools' `.part` files.
+fn is_tmp_file(file_path: &Path, profile: &Profile) -> bool {
+    let suffix_stem = Path::new(&profile.suffix)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(&profile.suffix);
+    file_path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .is_some_and(|name| {
+            TMP_FILE_ENDINGS.iter().any(|ending| name.ends_with(ending))
+                && name.contains(suffix_stem)
+        })
+}
+
+/// Outcome of `clean_tmp_files`.
+#[derive(Default)]
+pub struct CleanedTmpFiles {
+    pub removed: Vec<PathBuf>,
+    /// Entries that could not be read or removed, they are skipped.
+    pub errors: Vec<Error>,
+}
+
+/// Removes temporary outputs not modified for `max_age`, which running
+/// transcodes keep writing to.
+pub async fn clean_tmp_files(
+    path: &Path,
+    recursive: &bool,
+    max_age: Duration,
+    profile: &Profile,
+) -> CleanedTmpFiles {
+    let mut cleaned = CleanedTmpFiles::default();
+    clean_tmp_folder(path, *recursive, max_age, profile, &mut cleaned).await;
+    cleaned
+}
+
+#[async_recursion]
+async fn clean_tmp_folder(
+    path: &Path,
+    recursive: bool,
+    max_age: Duration,
+    profile: &Profile,
+    cleaned: &mut CleanedTmpFiles,
+) {
+    let mut read_dir = match read_dir(path).await.step("read folder", path) {
+        Ok(read_dir) => read_dir,
+        Err(e) => return cleaned.errors.push(e),
+    };
+
+    loop {
+        let entry = match read_dir.next_entry().await.step("read folder", path) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(e) => return cleaned.errors.push(e),
+        };
+        let file_path = entry.path();
+        let file_type: fs::FileType = match entry.file_type().await.step("read", &file_path) {
+            Ok(file_type) => file_type,
+            Err(e) => {
+                cleaned.errors.push(e);
+                continue;
+            }
+        };
+
+        if file_type.is_file() && is_tmp_file(&file_path, profile) {
+            let modified = entry
+                .metadata()
+                .await
+                .and_then(|metadata| metadata.modified())
+                .step("read", &file_path);
+            let age = match modified {
+                Ok(modified) => SystemTime::now()
+                    .duration_since(modified)
+                    .unwrap_or_default(),
+                Err(e) => {
+                    cleaned.errors.push(e);
+                    continue;
+                }
+            };
+            if age >= max_age {
+                match tokio::fs::remove_file(&file_path)
+                    .await
+                    .step("remove", &file_path)
+                {
+                    Ok(()) => cleaned.removed.push(file_path),
+                    Err(e) => cleaned.errors.push(e),
+                }
+            }
+        } else if file_type.is_dir() && recursive {
+            clean_tmp_folder(&file_path, recursive, max_age, profile, cleaned).await;
+        }
+    }
+}
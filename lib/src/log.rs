@@ -5,6 +5,7 @@ use tokio::sync::broadcast::Receiver;
 
 use crate::ffmpeg::FFMpegEvent;
 
+#[derive(Default)]
 pub struct LogEventHandler;
 
 impl LogEventHandler {
@@ -22,22 +23,29 @@ impl LogEventHandler {
             .unwrap()
             .progress_chars("#>-"),
         );
+        let mut duration = 0;
         while let Ok(event) = rx.recv().await {
             match event {
                 FFMpegEvent::START(context) => {
-                    let seconds = context.probe.format.duration.parse::<f64>().unwrap();
+                    let seconds = context.probe.format.duration_seconds().unwrap_or(0.0);
                     let path = Path::new(&context.input_path);
                     if let Some(file_name) = path.file_name().and_then(|s| s.to_str()) {
                         bar.set_prefix(file_name.to_string());
                     }
-                    bar.set_length(seconds.round() as u64);
+                    duration = seconds.round() as u64;
+                    bar.set_length(duration);
                     bar.reset_elapsed();
                     bar.reset_eta();
                 }
                 FFMpegEvent::PROGRESS(_, progress) => {
-                    bar.set_position(progress.out_time_us / 1_000_000);
+                    // Each pass goes through the whole file
+                    bar.set_length(duration * progress.passes as u64);
+                    bar.set_position(progress.total_out_time_us(duration * 1_000_000) / 1_000_000);
                     bar.set_message(progress.speed);
                 }
+                FFMpegEvent::WARNING(_, message) => {
+                    bar.suspend(|| eprintln!("{}", message));
+                }
                 FFMpegEvent::CLOSE() => {
                     break;
                 }
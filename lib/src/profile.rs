@@ -0,0 +1,85 @@
+use std::{collections::HashMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{
+    config::FFMpegConfig,
+    error::{Error, IoResultExt, Result},
+};
+
+pub const DEFAULT_PROFILE_NAME: &str = "default";
+
+/// Target format of a transcode: which codecs the output streams should use,
+/// which container they go in and how the output file is named.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Profile {
+    pub video_codec: String,
+
+    pub audio_codec: String,
+
+    pub audio_channels: u8,
+
+    /// Codec for tracks with more than `audio_channels` channels, which are
+    /// then kept next to their downmix. `copy` keeps them untouched and
+    /// leaving it unset only keeps the downmix.
+    pub surround_codec: Option<String>,
+
+    /// Codec used for text subtitles, they are dropped when unset.
+    pub subtitle_codec: Option<String>,
+
+    /// Keep HDR videos as is in 10 bits, they are tonemapped to SDR otherwise.
+    pub hdr: bool,
+
+    /// Container format passed to ffmpeg's `-f`.
+    pub format: String,
+
+    /// Appended to the sanitized title to build the output file name.
+    pub suffix: String,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            video_codec: "h264".into(),
+            audio_codec: "aac".into(),
+            audio_channels: 2,
+            surround_codec: None,
+            subtitle_codec: Some("mov_text".into()),
+            hdr: false,
+            format: "mp4".into(),
+            suffix: "h264.aac.stereo.remux.mp4".into(),
+        }
+    }
+}
+
+impl Profile {
+    /// Loads the profile selected by the config, falling back to the built-in
+    /// one when it is named `default` and not overridden in the profiles file.
+    pub async fn load(config: &FFMpegConfig) -> Result<Self> {
+        let mut profiles = match &config.profiles_file {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .await
+                    .step("read profiles file", Path::new(path))?;
+                toml::from_str::<HashMap<String, Profile>>(&content)
+                    .map_err(|e| Error::Profile(e.to_string()))?
+            }
+            None => HashMap::new(),
+        };
+
+        match profiles.remove(&config.profile) {
+            Some(profile) => Ok(profile),
+            None if config.profile == DEFAULT_PROFILE_NAME => Ok(Self::default()),
+            None => Err(Error::Profile(format!(
+                "unknown profile {}",
+                config.profile
+            ))),
+        }
+    }
+
+    pub fn supports_faststart(&self) -> bool {
+        matches!(self.format.as_str(), "mp4" | "mov" | "ipod")
+    }
+}
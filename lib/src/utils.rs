@@ -1,4 +1,29 @@
-pub fn get_output_file_name(name: &String) -> String {
+use std::{
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use tokio::fs;
+
+use crate::profile::Profile;
+
+pub fn get_output_file_name(name: &str, profile: &Profile) -> String {
     let re = regex::Regex::new(r"[^A-Za-z0-9]+").unwrap();
-    format!("{}.h264.aac.stereo.remux.mp4", re.replace_all(name, "."),)
+    format!("{}.{}", re.replace_all(name, "."), profile.suffix)
+}
+
+/// Renames a file, falling back to a copy when both paths are on different
+/// filesystems. The copy goes through a `.part` file so `to` is never left
+/// half written.
+pub async fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to).await {
+        Err(error) if error.kind() == ErrorKind::CrossesDevices => {
+            let mut partial = to.as_os_str().to_owned();
+            partial.push(".part");
+            fs::copy(from, &partial).await?;
+            fs::rename(&partial, to).await?;
+            fs::remove_file(from).await
+        }
+        result => result,
+    }
 }
@@ -0,0 +1,154 @@
+use std::fmt;
+
+use serde::Serialize;
+
+/// Reason why a stream, or the file as a whole, has to be transcoded.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ValidationIssueKind {
+    CodecMismatch {
+        codec_type: String,
+        codec: String,
+        expected: String,
+    },
+    BitrateTooHigh {
+        bit_rate: u32,
+        max: u32,
+    },
+    SizeTooLarge {
+        size: u64,
+        max: u64,
+    },
+    ResolutionTooHigh {
+        width: u32,
+        height: u32,
+        max_width: Option<u32>,
+        max_height: Option<u32>,
+    },
+    Hdr {
+        color_transfer: String,
+    },
+    PixelFormatMismatch {
+        pix_fmt: String,
+        expected: String,
+    },
+    ProfileMismatch {
+        profile: String,
+        expected: String,
+    },
+    LevelTooHigh {
+        level: String,
+        max: String,
+    },
+    TooManyChannels {
+        channels: u8,
+        max: u8,
+    },
+    UnsupportedSubtitle {
+        codec: String,
+    },
+    FilteredOut {
+        codec_type: String,
+    },
+    NotDefault {
+        codec_type: String,
+    },
+    MissingDownmix,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ValidationIssue {
+    /// Index of the offending stream, unset for file level issues.
+    pub stream: Option<u8>,
+    #[serde(flatten)]
+    pub kind: ValidationIssueKind,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ValidationReport {
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    pub fn push(&mut self, stream: Option<u8>, kind: ValidationIssueKind) {
+        self.issues.push(ValidationIssue { stream, kind });
+    }
+
+    pub fn messages(&self) -> Vec<String> {
+        self.issues.iter().map(|issue| issue.to_string()).collect()
+    }
+}
+
+impl fmt::Display for ValidationIssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CodecMismatch {
+                codec_type,
+                codec,
+                expected,
+            } => write!(f, "{} codec {} is not {}", codec_type, codec, expected),
+            Self::BitrateTooHigh { bit_rate, max } => {
+                write!(f, "bitrate {} is above {}", bit_rate, max)
+            }
+            Self::SizeTooLarge { size, max } => {
+                write!(f, "file size {} is above the target size {}", size, max)
+            }
+            Self::ResolutionTooHigh {
+                width,
+                height,
+                max_width,
+                max_height,
+            } => {
+                let limits = [("width", max_width), ("height", max_height)]
+                    .iter()
+                    .filter_map(|(name, max)| max.map(|max| format!("{} {}", name, max)))
+                    .collect::<Vec<_>>();
+                write!(
+                    f,
+                    "resolution {}x{} is above the max {}",
+                    width,
+                    height,
+                    limits.join(" and ")
+                )
+            }
+            Self::Hdr { color_transfer } => {
+                write!(f, "HDR ({}) video needs tonemapping to SDR", color_transfer)
+            }
+            Self::PixelFormatMismatch { pix_fmt, expected } => {
+                write!(f, "pixel format {} is not {}", pix_fmt, expected)
+            }
+            Self::ProfileMismatch { profile, expected } => {
+                write!(f, "profile {} is not compatible with {}", profile, expected)
+            }
+            Self::LevelTooHigh { level, max } => {
+                write!(f, "level {} is above {}", level, max)
+            }
+            Self::TooManyChannels { channels, max } => {
+                write!(f, "{} audio channels is more than {}", channels, max)
+            }
+            Self::UnsupportedSubtitle { codec } => {
+                write!(f, "{} subtitles can't be kept in the output", codec)
+            }
+            Self::FilteredOut { codec_type } => {
+                write!(f, "{} track is filtered out", codec_type)
+            }
+            Self::NotDefault { codec_type } => {
+                write!(f, "should be the default {} track", codec_type)
+            }
+            Self::MissingDownmix => write!(f, "surround audio has no downmixed track"),
+        }
+    }
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.stream {
+            Some(index) => write!(f, "stream #{}: {}", index, self.kind),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}